@@ -1,17 +1,32 @@
+use super::backoff::{retry_after, Backoff};
 use super::graphql::OperationProcessor;
+use super::http_client::HttpClientConfig;
+use super::metrics::{self, DropReason};
+use super::usage_compression::BodyCompression;
+use super::usage_spill::SpillQueue;
+use super::usage_window::{self, OperationRef};
 use graphql_parser::schema::{parse_schema, Document};
+use rand::Rng;
 use reqwest::Client;
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::{HashMap, VecDeque},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
-use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::{mpsc, watch, Mutex as AsyncMutex, Notify};
 
 static COMMIT: Option<&'static str> = option_env!("GITHUB_SHA");
 
+/// How often spilled reports are retried, when no report succeeded in the meantime
+const REPLAY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long reporting stops after the token was refused
+const UNAUTHORIZED_PAUSE: Duration = Duration::from_secs(300);
+/// Operations waiting to be recorded in the usage window, newer ones are skipped when it's full
+const USAGE_QUEUE_SIZE: usize = 10_000;
+
 #[derive(Serialize, Debug)]
 pub struct Report {
     size: usize,
@@ -67,36 +82,210 @@ pub struct ExecutionReport {
     pub operation_name: Option<String>,
 }
 
+impl ExecutionReport {
+    /// Rough memory footprint, used to cap the buffer
+    pub fn estimated_size(&self) -> usize {
+        let len = |value: &Option<String>| value.as_ref().map_or(0, |value| value.len());
+
+        std::mem::size_of::<ExecutionReport>()
+            + self.operation_body.len()
+            + len(&self.operation_name)
+            + len(&self.client_name)
+            + len(&self.client_version)
+    }
+}
+
+/// What to drop when the buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Make room for new operations
+    DropOldest,
+    /// Keep the buffered operations
+    DropNewest,
+    /// Keep a uniform sample of the operations seen since the last report
+    Sample,
+}
+
+impl OverflowPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::Sample => "sample",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BufferLimits {
+    pub max_operations: usize,
+    /// Unit: bytes, estimated with `ExecutionReport::estimated_size`
+    pub max_bytes: usize,
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: 10_000,
+            max_bytes: 50 * 1024 * 1024,
+            overflow: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+/// How a report is retried when sending it fails
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    pub backoff: Backoff,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            backoff: Backoff::new(Duration::from_millis(500), Duration::from_secs(30)),
+        }
+    }
+}
+
+/// Reporting stops for a while when the token is refused, or when the endpoint asks to slow down
+/// for longer than the retries would wait.
+/// Buffered operations stay in the buffer (within its limits) in the meantime.
+#[derive(Debug, Default)]
+struct Pause {
+    until: Option<Instant>,
+    /// The token was refused, it's logged once until a report goes through
+    unauthorized: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct State {
     buffer: VecDeque<ExecutionReport>,
     schema: Document<'static, String>,
+    limits: BufferLimits,
+    /// Estimated size of the buffered operations
+    bytes: usize,
+    /// Operations pushed since the last drain, the population `OverflowPolicy::Sample` samples from
+    seen: u64,
+    /// Operations dropped because the buffer was full, since the last drain
+    overflowed: u64,
 }
 
 impl State {
-    fn new(schema: Document<'static, String>) -> Self {
+    fn new(schema: Document<'static, String>, limits: BufferLimits) -> Self {
         Self {
             buffer: VecDeque::new(),
             schema,
+            limits,
+            bytes: 0,
+            seen: 0,
+            overflowed: 0,
         }
     }
 
+    /// Buffers the report, or drops it (or another one) according to the overflow policy.
+    /// Returns the number of buffered reports.
     pub fn push(&mut self, report: ExecutionReport) -> usize {
-        self.buffer.push_back(report);
+        self.seen += 1;
+        let size = report.estimated_size();
+
+        if size > self.limits.max_bytes {
+            self.overflow(1);
+            return self.buffer.len();
+        }
+
+        match self.limits.overflow {
+            OverflowPolicy::DropNewest => {
+                if self.is_full(size) {
+                    self.overflow(1);
+                } else {
+                    self.insert(report, size);
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                while self.is_full(size) {
+                    self.remove(0);
+                }
+                self.insert(report, size);
+            }
+            OverflowPolicy::Sample => {
+                if !self.is_full(size) {
+                    self.insert(report, size);
+                } else {
+                    // Reservoir sampling, every operation seen so far has the same chance to be kept
+                    let mut rng = rand::thread_rng();
+                    let index = rng.gen_range(0..self.seen);
+
+                    if index < self.buffer.len() as u64 {
+                        self.remove(index as usize);
+                        self.insert(report, size);
+
+                        while self.bytes > self.limits.max_bytes {
+                            self.remove(rng.gen_range(0..self.buffer.len()));
+                        }
+                    } else {
+                        self.overflow(1);
+                    }
+                }
+            }
+        }
+
         self.buffer.len()
     }
 
     pub fn drain(&mut self) -> Vec<ExecutionReport> {
+        if self.overflowed > 0 {
+            tracing::warn!(
+                "Dropped {} operations since the last report, the usage buffer was full (policy: {})",
+                self.overflowed,
+                self.limits.overflow.as_str()
+            );
+        }
+
+        metrics::usage_buffer_depth(-(self.buffer.len() as i64));
+        metrics::usage_buffer_bytes(-(self.bytes as i64));
+        self.bytes = 0;
+        self.seen = 0;
+        self.overflowed = 0;
+
         self.buffer.drain(0..).collect::<Vec<ExecutionReport>>()
     }
+
+    fn is_full(&self, incoming: usize) -> bool {
+        self.buffer.len() >= self.limits.max_operations.max(1)
+            || self.bytes + incoming > self.limits.max_bytes
+    }
+
+    fn insert(&mut self, report: ExecutionReport, size: usize) {
+        self.buffer.push_back(report);
+        self.bytes += size;
+        metrics::usage_buffer_depth(1);
+        metrics::usage_buffer_bytes(size as i64);
+    }
+
+    fn remove(&mut self, index: usize) {
+        if let Some(report) = self.buffer.remove(index) {
+            let size = report.estimated_size();
+            self.bytes = self.bytes.saturating_sub(size);
+            metrics::usage_buffer_depth(-1);
+            metrics::usage_buffer_bytes(-(size as i64));
+            self.overflow(1);
+        }
+    }
+
+    fn overflow(&mut self, count: u64) {
+        self.overflowed += count;
+        metrics::usage_operations_dropped(DropReason::Overflow, count);
+    }
 }
 
 impl Default for State {
     fn default() -> Self {
-        Self {
-            buffer: VecDeque::new(),
-            schema: Default::default(),
-        }
+        Self::new(Default::default(), BufferLimits::default())
     }
 }
 
@@ -110,6 +299,21 @@ pub struct UsageAgent {
     pub state: Arc<Mutex<State>>,
     processor: Arc<Mutex<OperationProcessor>>,
     client: Client,
+    /// Stops the task sending reports every 5 seconds
+    stop: Arc<watch::Sender<bool>>,
+    /// Reports that failed to be sent, replayed once the endpoint recovers
+    spill: Option<Arc<SpillQueue>>,
+    /// Wakes up the replay of spilled reports
+    replay: Arc<Notify>,
+    retry: RetryPolicy,
+    pause: Arc<Mutex<Pause>>,
+    compression: BodyCompression,
+    /// Held while a background flush runs, so at most one drained buffer is in flight
+    flushing: Arc<AsyncMutex<()>>,
+    /// Set by `shutdown`, reports still unsent at that point are spilled
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+    /// Operations to record in the usage window, processed in the background
+    usage: mpsc::Sender<UsageRecord>,
 }
 
 fn non_empty_string(value: Option<String>) -> Option<String> {
@@ -134,6 +338,25 @@ pub enum AgentError {
     RateLimited,
     #[error("unable to send report: {0}")]
     Unknown(String),
+    #[error("report rejected: {0}")]
+    Rejected(String),
+    #[error("unable to create the HTTP client: {0}")]
+    HttpClient(String),
+}
+
+impl AgentError {
+    /// Name of the variant, used as a metric attribute
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AgentError::Lock(_) => "lock",
+            AgentError::Unauthorized => "unauthorized",
+            AgentError::Forbidden => "forbidden",
+            AgentError::RateLimited => "rate_limited",
+            AgentError::Unknown(_) => "unknown",
+            AgentError::Rejected(_) => "rejected",
+            AgentError::HttpClient(_) => "http_client",
+        }
+    }
 }
 
 impl UsageAgent {
@@ -145,20 +368,29 @@ impl UsageAgent {
         connect_timeout: u64,
         request_timeout: u64,
         accept_invalid_certs: bool,
-    ) -> Self {
+        http_client: &HttpClientConfig,
+        buffer_limits: BufferLimits,
+        spill: Option<SpillQueue>,
+        retry: RetryPolicy,
+        compression: BodyCompression,
+    ) -> Result<Self, AgentError> {
         let schema = parse_schema::<String>(&schema)
             .expect("Failed to parse schema")
             .into_static();
-        let state = Arc::new(Mutex::new(State::new(schema)));
+        let state = Arc::new(Mutex::new(State::new(schema, buffer_limits)));
         let processor = Arc::new(Mutex::new(OperationProcessor::new()));
 
-        let client = reqwest::Client::builder()
+        let client = http_client
+            .apply(reqwest::Client::builder())
+            .map_err(AgentError::HttpClient)?
             .danger_accept_invalid_certs(accept_invalid_certs)
             .connect_timeout(Duration::from_secs(connect_timeout))
             .timeout(Duration::from_secs(request_timeout))
             .build()
-            .map_err(|err| err.to_string())
-            .expect("Couldn't instantiate the http client for reports sending!");
+            .map_err(|err| AgentError::HttpClient(err.to_string()))?;
+
+        let (stop, mut stop_signal) = watch::channel(false);
+        let (usage, mut usage_records) = mpsc::channel(USAGE_QUEUE_SIZE);
 
         let agent = Self {
             state,
@@ -167,22 +399,64 @@ impl UsageAgent {
             token,
             buffer_size,
             client,
+            stop: Arc::new(stop),
+            spill: spill.map(Arc::new),
+            replay: Arc::new(Notify::new()),
+            retry,
+            pause: Arc::new(Mutex::new(Pause::default())),
+            compression,
+            flushing: Arc::new(AsyncMutex::new(())),
+            deadline: Arc::new(watch::channel(None).0),
+            usage,
         };
 
         let agent_for_interval = AsyncMutex::new(Arc::new(agent.clone()));
 
         tokio::task::spawn(async move {
             loop {
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                    _ = stop_signal.changed() => break,
+                }
+
+                agent_for_interval.lock().await.spawn_flush();
+            }
+        });
+
+        let agent_for_usage = agent.clone();
+        let mut usage_stop_signal = agent.stop.subscribe();
 
-                let agent_ref = agent_for_interval.lock().await.clone();
-                tokio::task::spawn(async move {
-                    agent_ref.flush().await;
-                });
+        tokio::task::spawn(async move {
+            loop {
+                tokio::select! {
+                    record = usage_records.recv() => match record {
+                        Some(record) => agent_for_usage.record_usage(record),
+                        None => break,
+                    },
+                    _ = usage_stop_signal.changed() => break,
+                }
             }
         });
 
-        agent
+        if agent.spill.is_some() {
+            let agent_for_replay = agent.clone();
+            let mut stop_signal = agent.stop.subscribe();
+
+            tokio::task::spawn(async move {
+                loop {
+                    // Reports spilled before a restart are replayed right away
+                    agent_for_replay.replay_spilled().await;
+
+                    tokio::select! {
+                        _ = tokio::time::sleep(REPLAY_INTERVAL) => {}
+                        _ = agent_for_replay.replay.notified() => {}
+                        _ = stop_signal.changed() => break,
+                    }
+                }
+            });
+        }
+
+        Ok(agent)
     }
 
     fn produce_report(&self, reports: Vec<ExecutionReport>) -> Result<Report, AgentError> {
@@ -216,6 +490,7 @@ impl UsageAgent {
                 );
             match operation {
                 Err(e) => {
+                    metrics::usage_operation_dropped(DropReason::Processing);
                     tracing::warn!(
                         "Dropping operation \"{}\" (phase: PROCESSING): {}",
                         op.operation_name
@@ -268,6 +543,51 @@ impl UsageAgent {
         Ok(report)
     }
 
+    /// A handle queueing operations for the usage window, it doesn't need the agent (nor its locks)
+    pub fn usage_recorder(&self) -> UsageRecorder {
+        UsageRecorder {
+            sender: self.usage.clone(),
+        }
+    }
+
+    /// Records the schema coordinates of a queued operation in the usage window of the registry
+    fn record_usage(&self, record: UsageRecord) {
+        let Some(window) = usage_window::get() else {
+            return;
+        };
+
+        let operation = match (self.processor.lock(), self.state.lock()) {
+            (Ok(mut processor), Ok(state)) => {
+                processor.process(&record.operation_body, &state.schema)
+            }
+            _ => {
+                tracing::error!("Unable to acquire lock for the usage window in record_usage");
+                return;
+            }
+        };
+
+        // Invalid operations are reported (and dropped) with the usage report
+        let Ok(Some(operation)) = operation else {
+            return;
+        };
+
+        let client = match (
+            non_empty_string(record.client_name),
+            non_empty_string(record.client_version),
+        ) {
+            (Some(name), Some(version)) => Some(format!("{}@{}", name, version)),
+            (name, _) => name,
+        };
+
+        window.record(
+            &operation.coordinates,
+            OperationRef {
+                operation: non_empty_string(record.operation_name).unwrap_or(operation.hash),
+                client,
+            },
+        );
+    }
+
     pub fn add_report(&self, execution_report: ExecutionReport) -> Result<(), AgentError> {
         let size = self
             .state
@@ -276,6 +596,7 @@ impl UsageAgent {
                 AgentError::Lock(e.to_string())
             })?
             .push(execution_report);
+        metrics::usage_operation_buffered();
 
         self.flush_if_full(size)?;
 
@@ -283,15 +604,29 @@ impl UsageAgent {
     }
 
     pub async fn send_report(&self, report: Report) -> Result<(), AgentError> {
-        const DELAY_BETWEEN_TRIES: Duration = Duration::from_millis(500);
-        const MAX_TRIES: u8 = 3;
+        let body = serde_json::to_vec(&report).map_err(|e| AgentError::Unknown(e.to_string()))?;
+
+        self.send(body).await
+    }
 
-        let mut error_message = "unexpected error".to_string();
+    /// Sends the JSON body of a report.
+    /// Transport errors, timeouts (408), rate limits (429) and server errors (5xx) are retried with exponential backoff,
+    /// other responses are final. A `Retry-After` (429, 503) is honored, or pauses reporting when it's longer than the backoff allows.
+    async fn send(&self, body: Vec<u8>) -> Result<(), AgentError> {
+        // Compressing a full report is CPU-bound, it stays off the async workers
+        let compression = self.compression;
+        let body = tokio::task::spawn_blocking(move || compression.encode(body))
+            .await
+            .map_err(|e| AgentError::Unknown(e.to_string()))?;
+        let mut attempt: u32 = 0;
+
+        loop {
+            let mut request = self.client.post(self.endpoint.clone());
+            if let Some(encoding) = body.encoding {
+                request = request.header(reqwest::header::CONTENT_ENCODING, encoding);
+            }
 
-        for _ in 0..MAX_TRIES {
-            let resp = self
-                .client
-                .post(self.endpoint.clone())
+            let request = request
                 .header(
                     reqwest::header::AUTHORIZATION,
                     format!("Bearer {}", self.token.clone()),
@@ -300,65 +635,443 @@ impl UsageAgent {
                     reqwest::header::USER_AGENT,
                     format!("hive-apollo-router/{}", COMMIT.unwrap_or_else(|| "local")),
                 )
-                .json(&report)
-                .send()
-                .await
-                .map_err(|e| AgentError::Unknown(e.to_string()))?;
-
-            match resp.status() {
-                reqwest::StatusCode::OK => {
-                    return Ok(());
-                }
-                reqwest::StatusCode::UNAUTHORIZED => {
-                    return Err(AgentError::Unauthorized);
-                }
-                reqwest::StatusCode::FORBIDDEN => {
-                    return Err(AgentError::Forbidden);
-                }
-                reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                    return Err(AgentError::RateLimited);
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.body.clone());
+
+            let response = tokio::select! {
+                response = request.send() => response,
+                // The caller spills the report
+                _ = self.shutdown_timeout() => {
+                    return Err(AgentError::Unknown("the shutdown timeout has passed".to_string()));
                 }
-                _ => {
-                    error_message = format!(
-                        "({}) {}",
-                        resp.status().as_str(),
-                        resp.text().await.unwrap_or_default()
+            };
+
+            let (error, retry_after) = match response {
+                Err(e) => (AgentError::Unknown(e.to_string()), None),
+                Ok(resp) => match resp.status() {
+                    status if status.is_success() => {
+                        return Ok(());
+                    }
+                    reqwest::StatusCode::UNAUTHORIZED => {
+                        return Err(AgentError::Unauthorized);
+                    }
+                    reqwest::StatusCode::FORBIDDEN => {
+                        return Err(AgentError::Forbidden);
+                    }
+                    reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                        (AgentError::RateLimited, retry_after(resp.headers()))
+                    }
+                    status
+                        if status.is_server_error()
+                            || status == reqwest::StatusCode::REQUEST_TIMEOUT =>
+                    {
+                        let retry_after = match status {
+                            reqwest::StatusCode::SERVICE_UNAVAILABLE => retry_after(resp.headers()),
+                            _ => None,
+                        };
+                        let error = AgentError::Unknown(format!(
+                            "({}) {}",
+                            status.as_str(),
+                            resp.text().await.unwrap_or_default()
+                        ));
+                        (error, retry_after)
+                    }
+                    // Sending the same report again is not going to help
+                    status => {
+                        return Err(AgentError::Rejected(format!(
+                            "({}) {}",
+                            status.as_str(),
+                            resp.text().await.unwrap_or_default()
+                        )));
+                    }
+                },
+            };
+
+            if let Some(retry_after) = retry_after.filter(|d| *d > self.retry.backoff.max()) {
+                self.pause(retry_after);
+                tracing::warn!(
+                    "Usage reporting is paused for {:?}, as requested by GraphQL Hive",
+                    retry_after
+                );
+                return Err(error);
+            }
+
+            attempt += 1;
+            if attempt > self.retry.max_retries {
+                return Err(error);
+            }
+
+            let delay = self.retry.backoff.delay_with_jitter(attempt);
+            let delay = retry_after.map_or(delay, |retry_after| delay.max(retry_after));
+
+            // The next attempt would start after the shutdown timeout, the report is spilled instead
+            if self
+                .until_deadline()
+                .is_some_and(|remaining| remaining <= delay)
+            {
+                return Err(error);
+            }
+
+            tracing::debug!(
+                "Unable to send report (attempt {}), retrying in {:?}: {}",
+                attempt,
+                delay,
+                error
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Time left before the shutdown timeout, `None` while the agent is running
+    fn until_deadline(&self) -> Option<Duration> {
+        let deadline = *self.deadline.borrow();
+        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Resolves once the shutdown timeout has passed, never while the agent is running
+    async fn shutdown_timeout(&self) {
+        let mut deadline = self.deadline.subscribe();
+        // The agent holds the sender, waiting doesn't fail
+        let _ = deadline.wait_for(Option::is_some).await;
+
+        let deadline = (*deadline.borrow()).unwrap_or_else(Instant::now);
+        tokio::time::sleep_until(deadline.into()).await;
+    }
+
+    fn is_paused(&self) -> bool {
+        self.pause
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn pause(&self, duration: Duration) {
+        self.pause.lock().unwrap_or_else(|e| e.into_inner()).until =
+            Some(Instant::now() + duration);
+    }
+
+    /// Logs a failed report. A refused token pauses reporting and is logged once, not on every flush.
+    fn report_error(&self, error: &AgentError) {
+        match error {
+            AgentError::Unauthorized | AgentError::Forbidden => {
+                let mut pause = self.pause.lock().unwrap_or_else(|e| e.into_inner());
+                pause.until = Some(Instant::now() + UNAUTHORIZED_PAUSE);
+
+                if pause.unauthorized {
+                    tracing::debug!("{}", error);
+                } else {
+                    pause.unauthorized = true;
+                    tracing::error!(
+                        "{}, usage reporting is paused for {:?} (check HIVE_TOKEN)",
+                        error,
+                        UNAUTHORIZED_PAUSE
                     );
                 }
             }
-            tokio::time::sleep(DELAY_BETWEEN_TRIES).await;
+            _ => tracing::error!("{}", error),
         }
+    }
+
+    fn report_succeeded(&self) {
+        let mut pause = self.pause.lock().unwrap_or_else(|e| e.into_inner());
 
-        Err(AgentError::Unknown(error_message))
+        if pause.unauthorized {
+            pause.unauthorized = false;
+            tracing::info!("Usage reporting resumed");
+        }
     }
 
     pub fn flush_if_full(&self, size: usize) -> Result<(), AgentError> {
-        if size >= self.buffer_size {
-            let cloned_self = self.clone();
-            tokio::task::spawn(async move {
-                cloned_self.flush().await;
-            });
+        if size >= self.buffer_size && !self.is_paused() {
+            self.spawn_flush();
         }
 
         Ok(())
     }
 
+    /// Flushes in the background, unless the previous background flush is still running.
+    /// Operations keep coming into the buffer meanwhile, within its limits.
+    fn spawn_flush(&self) {
+        let Ok(flushing) = self.flushing.clone().try_lock_owned() else {
+            return;
+        };
+
+        let cloned_self = self.clone();
+        tokio::task::spawn(async move {
+            cloned_self.flush().await;
+            drop(flushing);
+        });
+    }
+
+    /// Keeps a report that failed to be sent on disk, when a spill directory is configured
+    async fn spill(&self, body: &[u8], operations: usize) {
+        let Some(spill) = &self.spill else {
+            metrics::usage_operations_dropped(DropReason::ReportFailed, operations as u64);
+            return;
+        };
+
+        match spill.save(body, operations).await {
+            Ok(_) => {
+                metrics::usage_report_spilled(operations);
+                tracing::info!(
+                    "Spilled a usage report of {} operations to disk",
+                    operations
+                )
+            }
+            Err(e) => {
+                metrics::usage_operations_dropped(DropReason::ReportFailed, operations as u64);
+                tracing::error!("Unable to spill the usage report: {}", e)
+            }
+        }
+    }
+
+    /// Sends the spilled reports, oldest first, until one fails.
+    /// A report the endpoint rejects is dropped, so it doesn't hold back the newer ones.
+    async fn replay_spilled(&self) {
+        let Some(spill) = &self.spill else {
+            return;
+        };
+
+        if self.is_paused() {
+            return;
+        }
+
+        let entries = match spill.entries().await {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("Unable to read the spilled usage reports: {}", e);
+                return;
+            }
+        };
+
+        for entry in entries {
+            // Another agent replaying the same directory (a reload) may have taken it already
+            let Some(entry) = spill.claim(&entry).await else {
+                continue;
+            };
+
+            let body = match spill.read(&entry).await {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("{}", e);
+                    spill.release(&entry).await;
+                    continue;
+                }
+            };
+
+            let start = Instant::now();
+            match self.send(body).await {
+                Ok(_) => {
+                    metrics::usage_report_sent(entry.operations, start.elapsed());
+                    tracing::debug!("Replayed {} spilled operations", entry.operations);
+                    spill.remove(&entry).await;
+                }
+                Err(e @ AgentError::Rejected(_)) => {
+                    metrics::usage_report_failed(&e, start.elapsed());
+                    metrics::usage_operations_dropped(
+                        DropReason::ReportFailed,
+                        entry.operations as u64,
+                    );
+                    tracing::warn!(
+                        "Dropped a spilled usage report of {} operations: {}",
+                        entry.operations,
+                        e
+                    );
+                    spill.remove(&entry).await;
+                }
+                Err(e) => {
+                    // Still down, the report is kept for the next attempt
+                    metrics::usage_report_failed(&e, start.elapsed());
+                    tracing::debug!("Unable to replay spilled usage reports: {}", e);
+                    spill.release(&entry).await;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Stops sending reports in the background, buffered reports stay until `flush` or `hand_over`
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    /// Whether both agents share the same buffer
+    pub fn is_same(&self, other: &UsageAgent) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    /// Moves the buffered reports to another agent (a reloaded plugin), they are processed against its schema.
+    /// Returns the number of reports moved.
+    pub fn hand_over(&self, to: &UsageAgent) -> Result<usize, AgentError> {
+        let execution_reports = drain_reports(&self.state);
+        let count = execution_reports.len();
+
+        let size = {
+            let mut state = to
+                .state
+                .lock()
+                .map_err(|e| AgentError::Lock(e.to_string()))?;
+            for execution_report in execution_reports {
+                state.push(execution_report);
+            }
+            state.buffer.len()
+        };
+
+        to.flush_if_full(size)?;
+
+        Ok(count)
+    }
+
+    /// Stops the background task, waits for the background flush in flight and sends the buffered reports.
+    /// Reports still unsent after `timeout` are spilled instead, so they're sent by the next process.
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.stop();
+        self.deadline.send_replace(Some(Instant::now() + timeout));
+
+        // The background flush honours the deadline too, and spills its report when it passes
+        let _flushing = self.flushing.lock().await;
+
+        if let Some(report) = self.take_report() {
+            self.deliver(&report, self.is_paused()).await;
+        }
+    }
+
     pub async fn flush(&self) {
+        // Operations stay in the buffer until the pause is over, or are spilled when the agent stops
+        let paused = self.is_paused();
+        if paused && !*self.stop.borrow() {
+            return;
+        }
+
+        if let Some(report) = self.take_report() {
+            self.deliver(&report, paused).await;
+        }
+    }
+
+    /// Drains the buffer into a serialized report, `None` when there is nothing to send
+    fn take_report(&self) -> Option<PendingReport> {
         let execution_reports = drain_reports(&self.state);
         let size = execution_reports.len();
 
-        if size > 0 {
-            match self.produce_report(execution_reports) {
-                Ok(report) => match self.send_report(report).await {
-                    Ok(_) => tracing::debug!("Reported {} operations", size),
-                    Err(e) => tracing::error!("{}", e),
-                },
-                Err(e) => tracing::error!("{}", e),
+        if size == 0 {
+            return None;
+        }
+
+        let start = Instant::now();
+
+        match self.produce_report(execution_reports) {
+            Ok(report) => {
+                let operations = report.size;
+                match serde_json::to_vec(&report) {
+                    Ok(body) => Some(PendingReport {
+                        body,
+                        operations,
+                        size,
+                    }),
+                    Err(e) => {
+                        metrics::usage_operations_dropped(
+                            DropReason::ReportFailed,
+                            operations as u64,
+                        );
+                        tracing::error!("Unable to serialize the usage report: {}", e);
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                metrics::usage_report_failed(&e, start.elapsed());
+                metrics::usage_operations_dropped(DropReason::ReportFailed, size as u64);
+                tracing::error!("{}", e);
+                None
+            }
+        }
+    }
+
+    /// Sends a report, or spills it when it can't be sent now
+    async fn deliver(&self, report: &PendingReport, paused: bool) {
+        if paused {
+            self.spill(&report.body, report.operations).await;
+            return;
+        }
+
+        let start = Instant::now();
+
+        match self.send(report.body.clone()).await {
+            Ok(_) => {
+                metrics::usage_report_sent(report.size, start.elapsed());
+                tracing::debug!("Reported {} operations", report.size);
+                self.report_succeeded();
+                // The endpoint is back, send what was spilled during the outage
+                self.replay.notify_one();
+            }
+            Err(e) => {
+                metrics::usage_report_failed(&e, start.elapsed());
+                self.report_error(&e);
+
+                match e {
+                    // Sending it again is not going to help
+                    AgentError::Rejected(_) => metrics::usage_operations_dropped(
+                        DropReason::ReportFailed,
+                        report.operations as u64,
+                    ),
+                    _ => self.spill(&report.body, report.operations).await,
+                }
             }
         }
     }
 }
 
+/// An operation waiting for its schema coordinates to be recorded in the usage window
+struct UsageRecord {
+    operation_body: String,
+    operation_name: Option<String>,
+    client_name: Option<String>,
+    client_version: Option<String>,
+}
+
+/// Queues operations for the usage window of the registry, on the request path.
+/// Called for every operation, before sampling, exclusion and the buffer limits, so the window sees all of the traffic.
+#[derive(Clone)]
+pub struct UsageRecorder {
+    sender: mpsc::Sender<UsageRecord>,
+}
+
+impl UsageRecorder {
+    pub fn record(
+        &self,
+        operation_body: String,
+        operation_name: Option<String>,
+        client_name: Option<String>,
+        client_version: Option<String>,
+    ) {
+        if !usage_window::is_enabled() {
+            return;
+        }
+
+        let record = UsageRecord {
+            operation_body,
+            operation_name,
+            client_name,
+            client_version,
+        };
+
+        // The request doesn't wait, under a burst some operations are missing from the window
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(record) {
+            tracing::debug!("The usage window queue is full, skipping an operation");
+        }
+    }
+}
+
+/// A report drained from the buffer, ready to be sent or spilled
+struct PendingReport {
+    body: Vec<u8>,
+    /// Operations in the report
+    operations: usize,
+    /// Execution reports drained from the buffer
+    size: usize,
+}
+
 fn drain_reports(state: &Arc<Mutex<State>>) -> Vec<ExecutionReport> {
     match state.lock() {
         Ok(mut state) => state.drain(),
@@ -368,3 +1081,391 @@ fn drain_reports(state: &Arc<Mutex<State>>) -> Vec<ExecutionReport> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AgentError, BufferLimits, ExecutionReport, OverflowPolicy, RetryPolicy, State, UsageAgent,
+    };
+    use crate::backoff::Backoff;
+    use crate::http_client::HttpClientConfig;
+    use crate::test_support::{StandIn, OK};
+    use crate::usage_compression::{BodyCompression, Compression};
+    use crate::usage_spill::SpillQueue;
+    use crate::usage_window::{self, OperationRef};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn agent(endpoint: String) -> UsageAgent {
+        UsageAgent::new(
+            "type Query { a: String }".to_string(),
+            "token".to_string(),
+            endpoint,
+            1000,
+            5,
+            5,
+            false,
+            &HttpClientConfig::default(),
+            BufferLimits::default(),
+            None,
+            RetryPolicy {
+                max_retries: 2,
+                backoff: Backoff::new(Duration::from_millis(10), Duration::from_millis(100)),
+            },
+            BodyCompression::default(),
+        )
+        .unwrap()
+    }
+
+    fn execution_report() -> ExecutionReport {
+        ExecutionReport {
+            client_name: None,
+            client_version: None,
+            timestamp: 0,
+            duration: Duration::from_millis(10),
+            ok: true,
+            errors: 0,
+            operation_body: "query A { a }".to_string(),
+            operation_name: Some("A".to_string()),
+        }
+    }
+
+    fn buffered(agent: &UsageAgent) -> usize {
+        agent.state.lock().unwrap().buffer.len()
+    }
+
+    #[tokio::test]
+    async fn sends_buffered_reports_on_shutdown() {
+        let usage = StandIn::serve(vec![Some(OK)]).await;
+
+        let agent = agent(usage.url("/usage"));
+        agent.add_report(execution_report()).unwrap();
+        agent.shutdown(Duration::from_secs(5)).await;
+
+        assert_eq!(buffered(&agent), 0);
+        assert!(usage.requests()[0].contains("\"size\":1"));
+    }
+
+    #[tokio::test]
+    async fn spills_failed_reports_and_replays_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = SpillQueue::new(dir.path(), u64::MAX, Duration::from_secs(60));
+
+        let mut unreachable = agent("http://127.0.0.1:1/usage".to_string());
+        unreachable.spill = Some(Arc::new(spill.clone()));
+        unreachable.add_report(execution_report()).unwrap();
+        unreachable.flush().await;
+
+        let entries = spill.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operations, 1);
+
+        // another process, once the endpoint is back
+        let usage = StandIn::serve(vec![Some(OK)]).await;
+        let mut recovered = agent(usage.url("/usage"));
+        recovered.spill = Some(Arc::new(spill.clone()));
+        recovered.replay_spilled().await;
+
+        assert!(usage.requests()[0].contains("\"size\":1"));
+        assert!(spill.entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn drops_spilled_reports_the_endpoint_rejects() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = SpillQueue::new(dir.path(), u64::MAX, Duration::from_secs(60));
+
+        let mut unreachable = agent("http://127.0.0.1:1/usage".to_string());
+        unreachable.spill = Some(Arc::new(spill.clone()));
+        for _ in 0..2 {
+            unreachable.add_report(execution_report()).unwrap();
+            unreachable.flush().await;
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        assert_eq!(spill.entries().await.unwrap().len(), 2);
+
+        // the oldest one doesn't hold back the next one
+        let usage = StandIn::serve(vec![
+            Some("HTTP/1.1 400 Bad Request\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            Some(OK),
+        ])
+        .await;
+        let mut recovered = agent(usage.url("/usage"));
+        recovered.spill = Some(Arc::new(spill.clone()));
+        recovered.replay_spilled().await;
+
+        assert_eq!(usage.requests().len(), 2);
+        assert!(spill.entries().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn spills_the_final_report_after_the_shutdown_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = SpillQueue::new(dir.path(), u64::MAX, Duration::from_secs(60));
+        // never answers
+        let usage = StandIn::serve(vec![None]).await;
+
+        let mut agent = agent(usage.url("/usage"));
+        agent.spill = Some(Arc::new(spill.clone()));
+        agent.add_report(execution_report()).unwrap();
+        agent.shutdown(Duration::from_millis(200)).await;
+
+        let entries = spill.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operations, 1);
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_background_flush_on_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill = SpillQueue::new(dir.path(), u64::MAX, Duration::from_secs(60));
+        // never answers, the background flush stays in flight
+        let usage = StandIn::serve(vec![None]).await;
+
+        let mut agent = agent(usage.url("/usage"));
+        agent.spill = Some(Arc::new(spill.clone()));
+        agent.add_report(execution_report()).unwrap();
+        agent.flush_if_full(1000).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(buffered(&agent), 0);
+
+        agent.shutdown(Duration::from_millis(200)).await;
+
+        // spilled once, by the background flush
+        let entries = spill.entries().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operations, 1);
+    }
+
+    #[tokio::test]
+    async fn retries_with_retry_after() {
+        let usage = StandIn::serve(vec![
+            Some("HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            Some("HTTP/1.1 503 Service Unavailable\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            Some(OK),
+        ])
+        .await;
+
+        agent(usage.url("/usage"))
+            .send(b"{}".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(usage.requests().len(), 3);
+
+        // asked to wait longer than the backoff allows
+        let usage = StandIn::serve(vec![Some(
+            "HTTP/1.1 429 Too Many Requests\r\nretry-after: 60\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+        )])
+        .await;
+        let agent = agent(usage.url("/usage"));
+
+        assert!(agent.send(b"{}".to_vec()).await.is_err());
+        assert_eq!(usage.requests().len(), 1);
+        assert!(agent.is_paused());
+    }
+
+    #[tokio::test]
+    async fn compresses_the_report() {
+        let usage = StandIn::serve(vec![Some(OK)]).await;
+
+        let mut agent = agent(usage.url("/usage"));
+        agent.compression = BodyCompression {
+            algorithm: Compression::Gzip,
+            threshold: 0,
+        };
+        agent.send(b"{}".to_vec()).await.unwrap();
+
+        let request = usage.requests()[0].to_lowercase();
+        assert!(request.contains("content-encoding: gzip"));
+        assert!(request.contains("content-type: application/json"));
+    }
+
+    #[tokio::test]
+    async fn retries_transport_errors() {
+        // the first connection is closed without a response
+        let usage = StandIn::serve(vec![Some(""), Some(OK)]).await;
+
+        agent(usage.url("/usage"))
+            .send(b"{}".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(usage.requests().len(), 2);
+
+        let agent = agent("http://127.0.0.1:1/usage".to_string());
+        assert!(matches!(
+            agent.send(b"{}".to_vec()).await,
+            Err(AgentError::Unknown(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn accepts_any_success_status() {
+        let usage = StandIn::serve(vec![
+            Some("HTTP/1.1 202 Accepted\r\nconnection: close\r\ncontent-length: 0\r\n\r\n"),
+            Some("HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n"),
+        ])
+        .await;
+        let agent = agent(usage.url("/usage"));
+
+        agent.send(b"{}".to_vec()).await.unwrap();
+        agent.send(b"{}".to_vec()).await.unwrap();
+        assert_eq!(usage.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_rejected_reports() {
+        let usage = StandIn::serve(vec![
+            Some(
+                "HTTP/1.1 413 Payload Too Large\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+            ),
+            Some(OK),
+        ])
+        .await;
+
+        assert!(matches!(
+            agent(usage.url("/usage")).send(b"{}".to_vec()).await,
+            Err(AgentError::Rejected(_))
+        ));
+        assert_eq!(usage.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pauses_when_the_token_is_refused() {
+        let usage = StandIn::serve(vec![Some(
+            "HTTP/1.1 401 Unauthorized\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+        )])
+        .await;
+        let agent = agent(usage.url("/usage"));
+
+        agent.add_report(execution_report()).unwrap();
+        agent.flush().await;
+        assert_eq!(usage.requests().len(), 1);
+        assert!(agent.is_paused());
+
+        // kept in the buffer, not sent
+        agent.add_report(execution_report()).unwrap();
+        agent.flush().await;
+        assert_eq!(usage.requests().len(), 1);
+        assert_eq!(buffered(&agent), 1);
+    }
+
+    #[tokio::test]
+    async fn records_the_usage_of_every_operation() {
+        let agent = agent("http://127.0.0.1:1".to_string());
+        let window = usage_window::enable(Duration::from_secs(3600));
+
+        agent.usage_recorder().record(
+            "query RecordedA { a }".to_string(),
+            Some("RecordedA".to_string()),
+            Some("web".to_string()),
+            Some("1.0".to_string()),
+        );
+        // recorded in the background
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let used = window.used(|coordinate| coordinate == "Query.a");
+        assert!(used["Query.a"].contains(&OperationRef {
+            operation: "RecordedA".to_string(),
+            client: Some("web@1.0".to_string()),
+        }));
+        // nothing is buffered, the window is fed before sampling
+        assert_eq!(buffered(&agent), 0);
+    }
+
+    #[tokio::test]
+    async fn flushes_one_full_buffer_at_a_time() {
+        // never answers, the first flush stays in flight
+        let usage = StandIn::serve(vec![None, None]).await;
+        let agent = agent(usage.url("/usage"));
+
+        agent.add_report(execution_report()).unwrap();
+        agent.flush_if_full(1000).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        agent.add_report(execution_report()).unwrap();
+        agent.flush_if_full(1000).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(usage.requests().len(), 1);
+        assert_eq!(buffered(&agent), 1);
+    }
+
+    #[tokio::test]
+    async fn hands_over_buffered_reports() {
+        let previous = agent("http://127.0.0.1:1/usage".to_string());
+        let reloaded = agent("http://127.0.0.1:1/usage".to_string());
+        previous.add_report(execution_report()).unwrap();
+        previous.add_report(execution_report()).unwrap();
+
+        assert!(!previous.is_same(&reloaded));
+        assert_eq!(previous.hand_over(&reloaded).unwrap(), 2);
+        assert_eq!(buffered(&previous), 0);
+        assert_eq!(buffered(&reloaded), 2);
+    }
+
+    fn named(name: &str) -> ExecutionReport {
+        ExecutionReport {
+            operation_name: Some(name.to_string()),
+            ..execution_report()
+        }
+    }
+
+    fn names(state: &mut State) -> Vec<String> {
+        state
+            .drain()
+            .into_iter()
+            .filter_map(|report| report.operation_name)
+            .collect()
+    }
+
+    #[test]
+    fn caps_the_buffer() {
+        let limits = |max_operations, max_bytes, overflow| BufferLimits {
+            max_operations,
+            max_bytes,
+            overflow,
+        };
+
+        let mut state = State::new(
+            Default::default(),
+            limits(2, usize::MAX, OverflowPolicy::DropOldest),
+        );
+        for name in ["a", "b", "c"] {
+            state.push(named(name));
+        }
+        assert_eq!(state.overflowed, 1);
+        assert_eq!(names(&mut state), vec!["b", "c"]);
+        assert_eq!((state.bytes, state.overflowed), (0, 0));
+
+        let mut state = State::new(
+            Default::default(),
+            limits(2, usize::MAX, OverflowPolicy::DropNewest),
+        );
+        for name in ["a", "b", "c"] {
+            state.push(named(name));
+        }
+        assert_eq!(names(&mut state), vec!["a", "b"]);
+
+        let mut state = State::new(
+            Default::default(),
+            limits(10, usize::MAX, OverflowPolicy::Sample),
+        );
+        for _ in 0..100 {
+            state.push(execution_report());
+        }
+        assert_eq!(state.buffer.len(), 10);
+        assert_eq!(state.overflowed, 90);
+
+        // two reports fit in the byte limit
+        let size = named("a").estimated_size();
+        let mut state = State::new(
+            Default::default(),
+            limits(10, size * 2, OverflowPolicy::DropOldest),
+        );
+        for name in ["a", "b", "c"] {
+            state.push(named(name));
+        }
+        assert_eq!(state.bytes, size * 2);
+        assert_eq!(names(&mut state), vec!["b", "c"]);
+    }
+}
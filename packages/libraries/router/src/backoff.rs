@@ -0,0 +1,109 @@
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use std::time::{Duration, SystemTime};
+
+/// Exponential backoff with jitter.
+/// The delay doubles with every attempt, starting at `base` and never exceeding `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+        }
+    }
+
+    /// The longest delay between attempts
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Delay before the next attempt, without jitter.
+    /// `attempt` starts at 1 for the first retry.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        self.base
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+
+    /// Delay before the next attempt, picked at random between half and the full exponential delay,
+    /// so that many routers failing at once don't hit the endpoint at the same moment.
+    pub fn delay_with_jitter(&self, attempt: u32) -> Duration {
+        let delay = self.delay(attempt);
+        let half = delay / 2;
+        let jitter_ms = half.as_millis() as u64;
+
+        if jitter_ms == 0 {
+            return delay;
+        }
+
+        half + Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_ms))
+    }
+}
+
+/// Reads the `Retry-After` header, both the delay-seconds and the HTTP-date forms.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    httpdate::parse_http_date(value)
+        .ok()
+        .map(|date| date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_after, Backoff};
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn doubles_until_max() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(4), Duration::from_secs(8));
+        assert_eq!(backoff.delay(5), Duration::from_secs(10));
+        assert_eq!(backoff.delay(100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+        for attempt in 1..10 {
+            let delay = backoff.delay_with_jitter(attempt);
+            assert!(delay >= backoff.delay(attempt) / 2);
+            assert!(delay <= backoff.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        headers.insert(RETRY_AFTER, HeaderValue::from_str(&date).unwrap());
+        let delay = retry_after(&headers).unwrap();
+        assert!(delay > Duration::from_secs(3500) && delay <= Duration::from_secs(3600));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(retry_after(&headers), None);
+    }
+}
@@ -0,0 +1,60 @@
+use sha2::Digest;
+use sha2::Sha256;
+use std::path::Path;
+
+/// Writes to a temporary file next to the target and renames it into place,
+/// so readers (like the router's file watcher) never pick up a half-written file.
+pub(crate) async fn write_atomically(file_name: &str, contents: &str) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let path = Path::new(file_name);
+    let tmp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        path.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        std::process::id()
+    ));
+
+    let result = async {
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        tokio::fs::rename(&tmp_path, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&tmp_path).await;
+    }
+
+    result
+}
+
+/// Uppercase hex SHA-256, used to identify supergraphs
+pub(crate) fn hash(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+    format!("{:X}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::write_atomically;
+
+    #[tokio::test]
+    async fn writes_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir
+            .path()
+            .join("supergraph.graphql")
+            .to_string_lossy()
+            .to_string();
+
+        write_atomically(&file_name, "first").await.unwrap();
+        write_atomically(&file_name, "second").await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&file_name).unwrap(), "second");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
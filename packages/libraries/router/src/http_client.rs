@@ -0,0 +1,300 @@
+use reqwest::{Certificate, ClientBuilder, Identity, NoProxy, Proxy};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::env;
+
+/// Network settings of the HTTP clients talking to GraphQL Hive, shared by the CDN and usage reporting clients.
+/// Without `proxy`, the `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY` environment variables apply.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HttpClientConfig {
+    /// URL of the HTTP(S) proxy
+    /// Environment variable: HIVE_HTTP_PROXY
+    proxy: Option<String>,
+    /// Comma-separated hosts that bypass the proxy
+    /// Default: the NO_PROXY environment variable
+    /// Environment variable: HIVE_NO_PROXY
+    no_proxy: Option<String>,
+    /// PEM files with extra root certificates, trusted on top of the system ones
+    /// Environment variable: HIVE_CA_FILES (comma-separated)
+    ca_files: Option<Vec<String>>,
+    /// PEM file with the client certificate (mTLS)
+    /// Environment variable: HIVE_CLIENT_CERT
+    client_cert: Option<String>,
+    /// PEM file with the private key of the client certificate
+    /// Environment variable: HIVE_CLIENT_KEY
+    client_key: Option<String>,
+}
+
+impl HttpClientConfig {
+    /// Fills the values missing in the config file with environment variables.
+    pub fn with_env(mut self) -> Self {
+        self.proxy = self.proxy.or_else(|| env::var("HIVE_HTTP_PROXY").ok());
+        self.no_proxy = self.no_proxy.or_else(|| env::var("HIVE_NO_PROXY").ok());
+        self.ca_files = match self.ca_files {
+            Some(ca_files) => Some(ca_files),
+            None => env::var("HIVE_CA_FILES")
+                .ok()
+                .map(|files| files.split(',').map(|f| f.trim().to_string()).collect()),
+        };
+        self.client_cert = self
+            .client_cert
+            .or_else(|| env::var("HIVE_CLIENT_CERT").ok());
+        self.client_key = self.client_key.or_else(|| env::var("HIVE_CLIENT_KEY").ok());
+
+        self
+    }
+
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, String> {
+        if let Some(proxy) = self.proxy.as_ref().filter(|proxy| !proxy.trim().is_empty()) {
+            let no_proxy = match &self.no_proxy {
+                Some(no_proxy) => NoProxy::from_string(no_proxy),
+                None => NoProxy::from_env(),
+            };
+            let proxy = Proxy::all(proxy.trim())
+                .map_err(|e| format!("invalid proxy {}: {}", proxy, e))?
+                .no_proxy(no_proxy);
+
+            builder = builder.proxy(proxy);
+        }
+
+        for ca_file in self.ca_files.iter().flatten().filter(|f| !f.is_empty()) {
+            let pem = read(ca_file)?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("invalid certificate in {}: {}", ca_file, e))?;
+
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+
+        match (&self.client_cert, &self.client_key) {
+            (Some(client_cert), Some(client_key)) => {
+                let mut pem = read(client_cert)?;
+                pem.push(b'\n');
+                pem.extend(read(client_key)?);
+
+                let identity = Identity::from_pem(&pem)
+                    .map_err(|e| format!("invalid client certificate {}: {}", client_cert, e))?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => return Err("client_cert and client_key must be set together for mTLS".to_string()),
+        }
+
+        Ok(builder)
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpClientConfig;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const RESPONSE: &str = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+
+    fn temp_file(dir: &TempDir, name: &str, contents: &str) -> String {
+        let path = dir.path().join(name).to_string_lossy().to_string();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    struct TlsServer {
+        url: String,
+        ca: Certificate,
+        ca_key: KeyPair,
+    }
+
+    /// A local TLS stand-in with a certificate for `localhost`, signed by a private CA.
+    /// With `client_auth`, it only accepts clients presenting a certificate signed by the same CA.
+    async fn serve_tls(client_auth: bool) -> TlsServer {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&server_key, &ca, &ca_key)
+            .unwrap();
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = match client_auth {
+            true => {
+                let mut roots = rustls::RootCertStore::empty();
+                roots.add(CertificateDer::from(ca.der().to_vec())).unwrap();
+                let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    provider,
+                )
+                .build()
+                .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            }
+            false => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(
+                vec![CertificateDer::from(server.der().to_vec())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(server_key.serialize_der())),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(socket).await {
+                        let mut buffer = [0; 4096];
+                        let _ = stream.read(&mut buffer).await;
+                        let _ = stream.write_all(RESPONSE.as_bytes()).await;
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+
+        TlsServer {
+            url: format!("https://localhost:{}", address.port()),
+            ca,
+            ca_key,
+        }
+    }
+
+    #[tokio::test]
+    async fn trusts_extra_root_certificates() {
+        let TlsServer { url, ca, .. } = serve_tls(false).await;
+        let dir = tempfile::tempdir().unwrap();
+
+        let client = reqwest::Client::builder().build().unwrap();
+        assert!(client.get(&url).send().await.is_err());
+
+        let config = HttpClientConfig {
+            ca_files: Some(vec![temp_file(&dir, "ca.pem", &ca.pem())]),
+            ..Default::default()
+        };
+        let client = config
+            .apply(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn presents_the_client_certificate() {
+        let TlsServer { url, ca, ca_key } = serve_tls(true).await;
+        let dir = tempfile::tempdir().unwrap();
+        let ca_file = temp_file(&dir, "ca.pem", &ca.pem());
+
+        let config = HttpClientConfig {
+            ca_files: Some(vec![ca_file.clone()]),
+            ..Default::default()
+        };
+        let client = config
+            .apply(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(client.get(&url).send().await.is_err());
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec!["router".to_string()]).unwrap();
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client_cert = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+
+        let config = HttpClientConfig {
+            ca_files: Some(vec![ca_file]),
+            client_cert: Some(temp_file(&dir, "cert.pem", &client_cert.pem())),
+            client_key: Some(temp_file(&dir, "key.pem", &client_key.serialize_pem())),
+            ..Default::default()
+        };
+        let client = config
+            .apply(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn sends_requests_through_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+
+        let request = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 4096];
+            let read = socket.read(&mut buffer).await.unwrap();
+            socket.write_all(RESPONSE.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buffer[..read]).to_string()
+        });
+
+        let config = HttpClientConfig {
+            proxy: Some(proxy),
+            no_proxy: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        let client = config
+            .apply(reqwest::Client::builder())
+            .unwrap()
+            .build()
+            .unwrap();
+        client
+            .get("http://cdn.graphql-hive.test/supergraph")
+            .send()
+            .await
+            .unwrap();
+
+        // a proxy receives the absolute URL
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("GET http://cdn.graphql-hive.test/supergraph HTTP/1.1"));
+    }
+
+    #[test]
+    fn requires_both_client_certificate_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = HttpClientConfig {
+            client_cert: Some(temp_file(&dir, "cert.pem", "")),
+            ..Default::default()
+        };
+        assert!(config.apply(reqwest::Client::builder()).is_err());
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["router".to_string()])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        let config = HttpClientConfig {
+            client_cert: Some(temp_file(&dir, "cert.pem", &cert.pem())),
+            client_key: Some(temp_file(&dir, "key.pem", &key.serialize_pem())),
+            ..Default::default()
+        };
+        assert!(config.apply(reqwest::Client::builder()).is_ok());
+    }
+}
@@ -1,5 +1,22 @@
 mod agent;
+mod backoff;
+mod fs_util;
 mod graphql;
+mod http_client;
+mod metrics;
 pub mod registry;
+pub mod registry_artifacts;
+mod registry_cache;
+mod registry_diff;
+mod registry_history;
+mod registry_hooks;
+mod registry_integrity;
+mod registry_local;
 pub mod registry_logger;
+pub mod registry_status;
+#[cfg(test)]
+mod test_support;
 pub mod usage;
+mod usage_compression;
+mod usage_spill;
+mod usage_window;
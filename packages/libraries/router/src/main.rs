@@ -1,19 +1,37 @@
 // Specify the modules our binary should include -- https://twitter.com/YassinEldeeb7/status/1468680104243077128
 mod agent;
+mod backoff;
+mod fs_util;
 mod graphql;
+mod http_client;
+mod metrics;
 mod registry;
+mod registry_artifacts;
+mod registry_cache;
+mod registry_diff;
+mod registry_history;
+mod registry_hooks;
+mod registry_integrity;
+mod registry_local;
 mod registry_logger;
+mod registry_status;
+#[cfg(test)]
+mod test_support;
 mod usage;
+mod usage_compression;
+mod usage_spill;
+mod usage_window;
 
+use anyhow::Result;
 use registry::HiveRegistry;
-use usage::register;
 
 fn main() {
-    // Register the usage reporting plugin
-    register();
+    // Register the registry and usage reporting plugins
+    registry::register();
+    usage::register();
 
     // Initialize the Hive Registry and start the Apollo Router
-    match HiveRegistry::new(None).and(apollo_router::main()) {
+    match run() {
         Ok(_) => {}
         Err(e) => {
             eprintln!("{}", e);
@@ -21,3 +39,50 @@ fn main() {
         }
     }
 }
+
+/// Mirrors `apollo_router::main`, but owns the runtime so the registry poller runs next to the router
+/// and is stopped once the router shuts down, after the last usage report is sent.
+fn run() -> Result<()> {
+    // The router reads its log filter when it starts, HIVE_REGISTRY_LOG is added to it
+    // before the runtime spawns any thread. It has no effect when the router is started with `--log`.
+    if let Some(filter) = registry_logger::router_log_filter() {
+        std::env::set_var("APOLLO_ROUTER_LOG", filter);
+    }
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+
+    if let Some(nb) = std::env::var("APOLLO_ROUTER_NUM_CORES")
+        .ok()
+        .and_then(|value| value.parse::<usize>().ok())
+    {
+        builder.worker_threads(nb);
+    }
+
+    let runtime = builder.build()?;
+
+    runtime.block_on(async {
+        let mut registry = HiveRegistry::new().await?;
+        let schema = registry
+            .as_mut()
+            .and_then(|registry| registry.take_schema_source());
+
+        let result = match schema {
+            // The supergraph file or stream of the registry, no environment variable is involved
+            Some(schema) => {
+                apollo_router::Executable::builder()
+                    .schema(schema)
+                    .start()
+                    .await
+            }
+            None => apollo_router::Executable::builder().start().await,
+        };
+
+        // Reports buffered by the usage plugin when the router stopped
+        usage::shutdown().await;
+        // The poller, started above or by the hive.registry plugin
+        registry::shutdown().await;
+
+        result
+    })
+}
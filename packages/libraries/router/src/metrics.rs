@@ -0,0 +1,128 @@
+//! Metrics of the registry and the usage agent.
+//! They are tracing events picked up by the router's telemetry pipeline,
+//! so they are exported like the router's own metrics (Prometheus, OTLP).
+use crate::agent::AgentError;
+use std::time::Duration;
+
+/// Outcome of a request to GraphQL Hive CDN
+#[derive(Debug, Clone, Copy)]
+pub enum FetchOutcome {
+    Ok,
+    NotModified,
+    Error,
+}
+
+impl FetchOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FetchOutcome::Ok => "ok",
+            FetchOutcome::NotModified => "not_modified",
+            FetchOutcome::Error => "error",
+        }
+    }
+}
+
+/// Why an operation is not reported
+#[derive(Debug, Clone, Copy)]
+pub enum DropReason {
+    Sampling,
+    Excluded,
+    Processing,
+    /// The buffer was full
+    Overflow,
+    /// The report could not be sent
+    ReportFailed,
+    /// A spilled report exceeded the size or age limit
+    SpillEvicted,
+}
+
+impl DropReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::Sampling => "sampling",
+            DropReason::Excluded => "excluded",
+            DropReason::Processing => "processing",
+            DropReason::Overflow => "overflow",
+            DropReason::ReportFailed => "report_failed",
+            DropReason::SpillEvicted => "spill_evicted",
+        }
+    }
+}
+
+pub fn registry_fetch(endpoint: &str, outcome: FetchOutcome, duration: Duration) {
+    let outcome = outcome.as_str();
+
+    tracing::info!(
+        monotonic_counter.hive.registry.fetch = 1u64,
+        endpoint = endpoint,
+        outcome = outcome
+    );
+    tracing::info!(
+        histogram.hive.registry.fetch.duration = duration.as_secs_f64(),
+        endpoint = endpoint,
+        outcome = outcome
+    );
+}
+
+/// A new supergraph was `applied` or `rejected`
+pub fn registry_schema_change(outcome: &'static str) {
+    tracing::info!(
+        monotonic_counter.hive.registry.schema.change = 1u64,
+        outcome = outcome
+    );
+}
+
+pub fn usage_operation_buffered() {
+    tracing::info!(monotonic_counter.hive.usage.operation.buffered = 1u64);
+}
+
+pub fn usage_operation_dropped(reason: DropReason) {
+    usage_operations_dropped(reason, 1);
+}
+
+pub fn usage_operations_dropped(reason: DropReason, count: u64) {
+    if count == 0 {
+        return;
+    }
+
+    tracing::info!(
+        monotonic_counter.hive.usage.operation.dropped = count,
+        reason = reason.as_str()
+    );
+}
+
+/// Tracks the number of buffered operations, `delta` is negative when the buffer is drained
+pub fn usage_buffer_depth(delta: i64) {
+    tracing::info!(counter.hive.usage.buffer.depth = delta);
+}
+
+/// Tracks the estimated size of the buffered operations
+pub fn usage_buffer_bytes(delta: i64) {
+    tracing::info!(counter.hive.usage.buffer.bytes = delta);
+}
+
+pub fn usage_report_sent(operations: usize, duration: Duration) {
+    tracing::info!(monotonic_counter.hive.usage.report.sent = 1u64);
+    tracing::info!(monotonic_counter.hive.usage.report.operations = operations as u64);
+    tracing::info!(
+        histogram.hive.usage.report.duration = duration.as_secs_f64(),
+        outcome = "ok"
+    );
+}
+
+/// A report that failed to be sent was saved to disk
+pub fn usage_report_spilled(operations: usize) {
+    tracing::info!(monotonic_counter.hive.usage.report.spilled = 1u64);
+    tracing::info!(monotonic_counter.hive.usage.report.spilled.operations = operations as u64);
+}
+
+pub fn usage_report_failed(error: &AgentError, duration: Duration) {
+    tracing::info!(
+        monotonic_counter.hive.usage.report.failed = 1u64,
+        error = error.kind()
+    );
+    tracing::info!(
+        histogram.hive.usage.report.duration = duration.as_secs_f64(),
+        outcome = "error"
+    );
+}
@@ -1,257 +1,2727 @@
-use crate::registry_logger::Logger;
-use anyhow::{anyhow, Result};
-use sha2::Digest;
-use sha2::Sha256;
+use crate::backoff::{retry_after, Backoff};
+use crate::fs_util::{hash, write_atomically};
+use crate::http_client::HttpClientConfig;
+use crate::metrics::{self, FetchOutcome};
+use crate::registry_artifacts::{
+    cdn_artifacts, retain_cdn_artifacts, update_cdn_artifact, CdnArtifact,
+};
+use crate::registry_cache::{now, SupergraphCache};
+use crate::registry_diff::{diff, SupergraphDiff};
+use crate::registry_history::{pin_matches, SupergraphHistory};
+use crate::registry_hooks::{ChangeHooks, SupergraphChange};
+use crate::registry_integrity::IntegrityCheck;
+use crate::registry_local::LocalSource;
+use crate::registry_logger;
+use crate::registry_status::{self, RegistryStatus};
+use crate::usage_window;
+use apollo_router::plugin::Plugin;
+use apollo_router::plugin::PluginInit;
+use apollo_router::register_plugin;
+use apollo_router::services::{router, supergraph};
+use apollo_router::{Endpoint, ListenAddr, SchemaSource};
+use futures::channel::mpsc;
+use futures::StreamExt;
+use graphql_parser::schema::{parse_schema, Definition, TypeDefinition};
+use multimap::MultiMap;
+use reqwest::header::HeaderValue;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::Write;
-use std::thread;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tower::service_fn;
+use tower::BoxError;
+use tower::ServiceBuilder;
+use tower::ServiceExt;
+use tracing::instrument::WithSubscriber;
+use tracing::level_filters::LevelFilter;
 
 #[derive(Debug, Clone)]
 pub struct HiveRegistry {
-    endpoint: String,
-    key: String,
-    file_name: String,
-    etag: Option<String>,
-    accept_invalid_certs: bool,
-    pub logger: Logger,
+    /// Ordered list of CDN endpoints, the first one that responds wins
+    endpoints: Vec<CdnEndpoint>,
+    /// Index of the endpoint that served the latest response
+    active_endpoint: Option<usize>,
+    key: HeaderValue,
+    target: SupergraphTarget,
+    /// The supergraph currently used by the router
+    supergraph: Option<String>,
+    supergraph_hash: Option<String>,
+    poll_interval: Duration,
+    /// A single pooled client, shared by the initial fetch and every poll
+    client: reqwest::Client,
+    backoff: Backoff,
+    circuit: CircuitState,
+    /// Number of consecutive failures that opens the circuit
+    failure_threshold: u32,
+    consecutive_failures: u32,
+    /// Delay requested by the CDN with the last failed response
+    retry_after: Option<Duration>,
+    cache: Option<SupergraphCache>,
+    startup_policy: StartupPolicy,
+    startup_timeout: Duration,
+    fallback_schema_path: Option<String>,
+    usage_guard: UsageGuard,
+    usage_guard_window: Duration,
+    /// Artifacts fetched next to the supergraph
+    artifacts: Vec<CdnArtifact>,
+    /// Hash or version of the only supergraph to apply.
+    /// Replaced with the hash of the supergraph once it's applied.
+    pin: Option<String>,
+    history: Option<SupergraphHistory>,
+    /// Unit: seconds since UNIX epoch
+    last_fetched_at: Option<u64>,
+    last_error: Option<String>,
+    /// Verifies the supergraph before it's applied
+    integrity: Option<IntegrityCheck>,
+    /// Notified when `poll` applies a new supergraph
+    hooks: Option<ChangeHooks>,
+    /// Reads the supergraph from disk instead of the CDN, the only endpoint is its path
+    local: Option<LocalSource>,
+    /// The latest supergraph that was not applied
+    rejected: Option<RejectedSupergraph>,
 }
 
-pub struct HiveRegistryConfig {
-    endpoint: Option<String>,
-    key: Option<String>,
-    poll_interval: Option<u64>,
-    accept_invalid_certs: Option<bool>,
-    schema_file_path: Option<String>,
+#[derive(Debug, Clone)]
+struct RejectedSupergraph {
+    hash: String,
+    /// The supergraph held back by the usage guard, checked again while the CDN answers with 304
+    held: Option<String>,
 }
 
-static COMMIT: Option<&'static str> = option_env!("GITHUB_SHA");
+/// How the supergraph is handed over to the router
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaDelivery {
+    /// Write the supergraph to `schema_file_path` and let the router watch it (hot reload)
+    File,
+    /// Stream the supergraph to the router, no file is involved
+    Memory,
+}
 
-impl HiveRegistry {
-    pub fn new(user_config: Option<HiveRegistryConfig>) -> Result<()> {
-        let mut config = HiveRegistryConfig {
-            endpoint: None,
-            key: None,
-            poll_interval: None,
-            accept_invalid_certs: Some(true),
-            schema_file_path: None,
-        };
+impl FromStr for SchemaDelivery {
+    type Err = String;
 
-        // Pass values from user's config
-        if let Some(user_config) = user_config {
-            config.endpoint = user_config.endpoint;
-            config.key = user_config.key;
-            config.poll_interval = user_config.poll_interval;
-            config.accept_invalid_certs = user_config.accept_invalid_certs;
-            config.schema_file_path = user_config.schema_file_path;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "file" => Ok(SchemaDelivery::File),
+            "memory" => Ok(SchemaDelivery::Memory),
+            _ => Err(format!(
+                "unknown schema delivery \"{}\", expected one of: file, memory",
+                s
+            )),
         }
+    }
+}
+
+/// What to do with a supergraph that removes schema coordinates used by recent traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageGuard {
+    /// Don't track the usage
+    Off,
+    /// Apply the supergraph and log the affected operations
+    Warn,
+    /// Keep the current supergraph until the removed coordinates are no longer used
+    Hold,
+}
 
-        // Pass values from environment variables if they are not set in the user's config
+impl FromStr for UsageGuard {
+    type Err = String;
 
-        if config.endpoint.is_none() {
-            if let Ok(endpoint) = env::var("HIVE_CDN_ENDPOINT") {
-                config.endpoint = Some(endpoint);
-            }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(UsageGuard::Off),
+            "warn" => Ok(UsageGuard::Warn),
+            "hold" => Ok(UsageGuard::Hold),
+            _ => Err(format!(
+                "unknown usage guard \"{}\", expected one of: off, warn, hold",
+                s
+            )),
         }
+    }
+}
 
-        if config.key.is_none() {
-            if let Ok(key) = env::var("HIVE_CDN_KEY") {
-                config.key = Some(key);
-            }
+/// Which supergraph to fetch from the CDN
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SupergraphArtifact {
+    /// The supergraph of the target, the endpoint may or may not end with `/supergraph`
+    Default,
+    /// The supergraph of a contract, `<endpoint>/contracts/<name>/supergraph`
+    Contract(String),
+    /// Any artifact, `<endpoint>/<path>`
+    Path(String),
+}
+
+#[derive(Debug, Clone)]
+enum SupergraphTarget {
+    File(String),
+    Stream(mpsc::UnboundedSender<String>),
+}
+
+/// State of the CDN circuit breaker.
+/// `Open` means the CDN failed too many times in a row and polling is backing off,
+/// `HalfOpen` means a trial request is in flight after a back-off period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half-open"),
         }
+    }
+}
 
-        if config.poll_interval.is_none() {
-            if let Ok(poll_interval) = env::var("HIVE_CDN_POLL_INTERVAL") {
-                config.poll_interval = Some(
-                    poll_interval
-                        .parse()
-                        .expect("failed to parse HIVE_CDN_POLL_INTERVAL"),
-                );
-            }
+/// What to do when the supergraph can't be fetched at startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupPolicy {
+    /// Return an error right away
+    Fail,
+    /// Keep retrying until the startup timeout
+    Wait,
+    /// Start from the cached supergraph or the fallback schema file
+    Fallback,
+}
+
+impl FromStr for StartupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fail" => Ok(StartupPolicy::Fail),
+            "wait" => Ok(StartupPolicy::Wait),
+            "fallback" => Ok(StartupPolicy::Fallback),
+            _ => Err(format!(
+                "unknown startup policy \"{}\", expected one of: fail, wait, fallback",
+                s
+            )),
         }
+    }
+}
 
-        if config.accept_invalid_certs.is_none() {
-            if let Ok(accept_invalid_certs) = env::var("HIVE_CDN_ACCEPT_INVALID_CERTS") {
-                config.accept_invalid_certs = Some(
-                    accept_invalid_certs.eq("1")
-                        || accept_invalid_certs.to_lowercase().eq("true")
-                        || accept_invalid_certs.to_lowercase().eq("on"),
-                );
-            }
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("environment variable HIVE_CDN_ENDPOINT not found")]
+    MissingEndpoint,
+    #[error("environment variable HIVE_CDN_KEY not found")]
+    MissingKey,
+    #[error("invalid GraphQL Hive registry configuration: {0}")]
+    InvalidConfig(String),
+    #[error("failed to build the http client for GraphQL Hive CDN: {0}")]
+    HttpClient(String),
+    #[error("failed to fetch the supergraph from GraphQL Hive: {0}")]
+    InitialSupergraph(String),
+    #[error("failed to fetch the supergraph from GraphQL Hive within {timeout:?}: {last_error}")]
+    StartupTimeout {
+        timeout: Duration,
+        last_error: String,
+    },
+    #[error("failed to fetch the supergraph from GraphQL Hive ({error}) and to fall back ({fallback_error})")]
+    Fallback {
+        error: String,
+        fallback_error: String,
+    },
+}
+
+#[derive(Debug)]
+struct FetchError {
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl FetchError {
+    fn new(message: impl ToString) -> Self {
+        Self {
+            message: message.to_string(),
+            retry_after: None,
         }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CdnEndpoint {
+    url: String,
+    /// ETags are tracked per endpoint, mirrors don't necessarily share them
+    etag: Option<String>,
+    artifact_etags: HashMap<CdnArtifact, String>,
+}
 
-        if config.schema_file_path.is_none() {
-            if let Ok(schema_file_path) = env::var("HIVE_CDN_SCHEMA_FILE_PATH") {
-                config.schema_file_path = Some(schema_file_path);
+impl CdnEndpoint {
+    fn new(url: &str, artifact: &SupergraphArtifact) -> Self {
+        let mut url = url.trim().to_string();
+
+        match artifact {
+            SupergraphArtifact::Default => {
+                if !url.ends_with("/supergraph") {
+                    if url.ends_with("/") {
+                        url.push_str("supergraph")
+                    } else {
+                        url.push_str("/supergraph")
+                    }
+                }
+            }
+            SupergraphArtifact::Contract(name) => {
+                url = format!(
+                    "{}/contracts/{}/supergraph",
+                    target_url(&url),
+                    encode_path_segment(name)
+                )
+            }
+            SupergraphArtifact::Path(path) => {
+                url = format!("{}/{}", target_url(&url), path.trim_start_matches('/'))
             }
         }
 
-        // Resolve values
-        let mut endpoint = config.endpoint.unwrap_or_else(|| "".to_string());
-        let key = config.key.unwrap_or_else(|| "".to_string());
-        let poll_interval: u64 = match config.poll_interval {
-            Some(value) => value,
-            None => 10,
+        Self {
+            url,
+            etag: None,
+            artifact_etags: HashMap::new(),
+        }
+    }
+
+    /// The path of a local source, used as is
+    fn local(path: &Path) -> Self {
+        Self {
+            url: path.to_string_lossy().to_string(),
+            etag: None,
+            artifact_etags: HashMap::new(),
+        }
+    }
+
+    /// Other artifacts live next to the supergraph, they replace the last segment of its URL
+    fn artifact_url(&self, artifact: CdnArtifact) -> String {
+        let base = match self.url.rfind('/') {
+            Some(index) => &self.url[..index],
+            None => self.url.as_str(),
         };
-        let accept_invalid_certs = config.accept_invalid_certs.unwrap_or_else(|| false);
 
-        let logger = Logger::new();
+        format!("{}/{}", base, artifact.path())
+    }
+}
 
-        // In case of an endpoint and an key being empty, we don't start the polling and skip the registry
-        if endpoint.is_empty() && key.is_empty() {
-            logger.info("You're not using GraphQL Hive as the source of schema.");
-            logger.info(
-                "Reason: could not find HIVE_CDN_KEY and HIVE_CDN_ENDPOINT environment variables.",
-            );
-            return Ok(());
-        }
+/// The URL of the target, endpoints copied from the default setup end with `/supergraph`
+fn target_url(endpoint: &str) -> &str {
+    let endpoint = endpoint.trim_end_matches('/');
 
-        // Throw if endpoint is empty
-        if endpoint.is_empty() {
-            return Err(anyhow!("environment variable HIVE_CDN_ENDPOINT not found",));
-        }
+    endpoint
+        .strip_suffix("/supergraph")
+        .unwrap_or(endpoint)
+        .trim_end_matches('/')
+}
 
-        if !endpoint.ends_with("/supergraph") {
-            if endpoint.ends_with("/") {
-                endpoint.push_str("supergraph")
-            } else {
-                endpoint.push_str("/supergraph")
+/// Percent-encodes everything but unreserved characters, contract names are free text
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
             }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Response of the CDN, `body` is `None` when the artifact did not change
+struct CdnResponse {
+    etag: Option<String>,
+    body: Option<String>,
+    headers: reqwest::header::HeaderMap,
+}
+
+/// Returned by `HiveRegistry::new` once the initial supergraph is fetched.
+/// The poller keeps running in the background and is handed over to the `hive.registry` plugin,
+/// call `registry::shutdown` once the router stopped.
+pub struct HiveRegistryHandle {
+    schema: Option<SchemaSource>,
+}
+
+impl HiveRegistryHandle {
+    /// The schema source to build the router with: the watched supergraph file, or the stream of supergraphs
+    /// when the schema is delivered in memory. It can be taken only once.
+    pub fn take_schema_source(&mut self) -> Option<SchemaSource> {
+        self.schema.take()
+    }
+}
+
+static POLLER: OnceLock<Mutex<Option<Poller>>> = OnceLock::new();
+/// Where the router started by `HiveRegistry::new` reads the supergraph from, it can't change once the router is started
+static ROUTER_TARGET: OnceLock<SupergraphTarget> = OnceLock::new();
+
+/// The background polling task.
+/// There's a single poller per process, kept across reloads of the `hive.registry` plugin
+/// and restarted when a reload changes its configuration.
+struct Poller {
+    /// The resolved configuration the poller was started with
+    config: HiveRegistryConfig,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Poller {
+    async fn stop(self) {
+        // The receiver lives as long as the task, so an error here means the task is already gone
+        let _ = self.shutdown.send(true);
+
+        if let Err(e) = self.task.await {
+            tracing::error!("Hive Registry poller failed to stop cleanly: {}", e);
         }
+    }
+}
 
-        // Throw if key is empty
-        if key.is_empty() {
-            return Err(anyhow!("environment variable HIVE_CDN_KEY not found"));
+fn poller() -> MutexGuard<'static, Option<Poller>> {
+    POLLER
+        .get_or_init(|| Mutex::new(None))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts polling with the configuration of the `hive.registry` plugin, or keeps the running poller when it's unchanged.
+/// `supergraph` is the one the router was just built with, a restarted poller starts from it.
+async fn attach(config: HiveRegistryConfig, supergraph: &str) -> Result<(), RegistryError> {
+    let previous = {
+        let mut poller = poller();
+        match poller.as_ref() {
+            Some(running) if running.config == config => return Ok(()),
+            _ => poller.take(),
         }
+    };
 
-        // A hacky way to force the router to use GraphQL Hive CDN as the source of schema.
-        // Our plugin does the polling and saves the supergraph to a file.
-        // It also enables hot-reloading to makes sure Apollo Router watches the file.
-        let file_name = config.schema_file_path.unwrap_or(
-            env::temp_dir()
-                .with_file_name("supergraph-schema.graphql")
-                .to_string_lossy()
-                .to_string(),
-        );
-        env::set_var("APOLLO_ROUTER_SUPERGRAPH_PATH", file_name.clone());
-        env::set_var("APOLLO_ROUTER_HOT_RELOAD", "true");
+    if let Some(previous) = previous {
+        tracing::info!("The registry configuration changed, restarting the poller");
+        previous.stop().await;
+    }
 
-        let mut registry = HiveRegistry {
-            endpoint,
-            key,
-            file_name,
-            etag: None,
-            accept_invalid_certs,
-            logger,
+    let target = ROUTER_TARGET.get().cloned();
+    let Some((mut registry, _)) = HiveRegistry::configure(config.clone(), target.clone())? else {
+        return Ok(());
+    };
+
+    // Without HIVE_CDN_* environment variables, the router was started with a supergraph of its own
+    if target.is_none() && !reads_the_supergraph_file(&config, &registry.target, supergraph).await {
+        return Err(RegistryError::InvalidConfig(
+            "the registry is configured in router.yaml only, and the router doesn't read the supergraph it delivers. Configure it with HIVE_CDN_* environment variables, or start the router with `--supergraph <schema_file_path> --hot-reload` and set schema_file_path (schema_delivery: file)".to_string(),
+        ));
+    }
+
+    registry.supergraph_hash = Some(hash(supergraph.as_bytes()));
+    registry.supergraph = Some(supergraph.to_string());
+    registry.publish_status();
+    registry.spawn(config, Duration::ZERO);
+
+    Ok(())
+}
+
+/// Whether the router reads `target`: an explicit `schema_file_path` holding the supergraph the router was started with.
+async fn reads_the_supergraph_file(
+    config: &HiveRegistryConfig,
+    target: &SupergraphTarget,
+    supergraph: &str,
+) -> bool {
+    match (target, &config.schema_file_path) {
+        (SupergraphTarget::File(file_name), Some(_)) => tokio::fs::read_to_string(file_name)
+            .await
+            .is_ok_and(|contents| contents == supergraph),
+        _ => false,
+    }
+}
+
+/// Stops the poller and waits for the poll in progress to finish.
+pub async fn shutdown() {
+    let poller = poller().take();
+
+    if let Some(poller) = poller {
+        poller.stop().await;
+    }
+}
+
+/// Configuration of the registry, the `hive.registry` section of `router.yaml`.
+///
+/// Values are resolved in the following order:
+/// the config file (`router.yaml`), then `HIVE_CDN_*` environment variables, then the defaults.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct HiveRegistryConfig {
+    /// Ordered list of CDN endpoints, the next one is tried when the previous one fails
+    /// Environment variable: HIVE_CDN_ENDPOINT (comma-separated)
+    endpoints: Option<Vec<String>>,
+    /// CDN access token
+    /// Environment variable: HIVE_CDN_KEY
+    key: Option<String>,
+    /// Name of the contract to fetch the supergraph of, instead of the target's supergraph
+    /// Environment variable: HIVE_CDN_CONTRACT
+    contract: Option<String>,
+    /// Path of the supergraph artifact, relative to the endpoints (for example `contracts/public/supergraph`).
+    /// Endpoints are used as is, without the `/supergraph` suffix.
+    /// Environment variable: HIVE_CDN_ARTIFACT_PATH
+    artifact_path: Option<String>,
+    /// A supergraph file, or a directory of versioned supergraph files (the latest one by name is used),
+    /// to read instead of GraphQL Hive CDN. Meant for local development, `endpoints` and `key` are ignored.
+    /// Environment variable: HIVE_REGISTRY_LOCAL_SOURCE
+    local_source: Option<String>,
+    /// Unit: seconds
+    /// Default: 10 (s), 1 (s) with `local_source`
+    poll_interval: Option<u64>,
+    /// Accept invalid SSL certificates, only use it for testing
+    /// Default: false
+    accept_invalid_certs: Option<bool>,
+    /// Where to save the supergraph
+    /// Default: <tmp-dir>/supergraph-schema.graphql
+    schema_file_path: Option<String>,
+    /// Unit: seconds
+    /// Default: 5 (s)
+    connect_timeout: Option<u64>,
+    /// Unit: seconds
+    /// Default: 15 (s)
+    request_timeout: Option<u64>,
+    /// Upper bound of the delay between polls after consecutive failures
+    /// Unit: seconds
+    /// Default: 300 (s)
+    max_backoff: Option<u64>,
+    /// Number of consecutive failures that opens the circuit breaker
+    /// Default: 3
+    failure_threshold: Option<u32>,
+    /// Directory holding the last known good supergraph, used when the CDN is unreachable at startup
+    cache_dir: Option<String>,
+    /// Maximum age of the cached supergraph to start from, since the CDN last served or confirmed it
+    /// Unit: seconds
+    cache_max_age: Option<u64>,
+    /// Default: fallback
+    startup_policy: Option<StartupPolicy>,
+    /// How long the `wait` startup policy keeps retrying
+    /// Unit: seconds
+    /// Default: 60 (s)
+    startup_timeout: Option<u64>,
+    /// Schema file used by the `fallback` startup policy when there's no cached supergraph
+    fallback_schema_path: Option<String>,
+    /// The router is built with `HiveRegistryHandle::take_schema_source`, the environment is left untouched
+    /// Default: file
+    schema_delivery: Option<SchemaDelivery>,
+    /// Checks whether a new supergraph removes fields used by recent traffic (requires the `hive.usage` plugin)
+    /// Default: off
+    usage_guard: Option<UsageGuard>,
+    /// How long a used schema coordinate is remembered
+    /// Unit: seconds
+    /// Default: 3600 (s)
+    usage_guard_window: Option<u64>,
+    /// Artifacts to fetch next to the supergraph: `services`, `sdl` and `metadata`.
+    /// Subgraphs and metadata are added to the router's `Context` (`hive::subgraphs` and `hive::metadata`).
+    /// Environment variable: HIVE_CDN_ARTIFACTS (comma-separated)
+    artifacts: Option<Vec<CdnArtifact>>,
+    /// SHA-256 or version (ETag) of the supergraph to stay on, newer supergraphs are not applied.
+    /// Looked up in the history first, so a rollback doesn't need the CDN.
+    /// Environment variable: HIVE_CDN_PIN
+    pin: Option<String>,
+    /// Directory holding the last applied supergraphs, keyed by hash
+    /// Environment variable: HIVE_CDN_HISTORY_DIR
+    history_dir: Option<String>,
+    /// Number of supergraphs kept in the history
+    /// Default: 10
+    history_size: Option<usize>,
+    /// Path of the registry status endpoint, readiness is served at `<status_path>/ready`.
+    /// The endpoint is disabled when not set.
+    /// Environment variable: HIVE_CDN_STATUS_PATH
+    status_path: Option<String>,
+    /// Address of the registry status endpoint
+    /// Default: 127.0.0.1:8088 (the router's health check)
+    status_listen: Option<SocketAddr>,
+    /// Readiness fails when the supergraph was not confirmed by the CDN for longer than this
+    /// Unit: seconds
+    max_schema_age: Option<u64>,
+    /// Response header holding the SHA-256 of the supergraph (hex or base64), the supergraph is rejected when it doesn't match
+    /// Environment variable: HIVE_CDN_CHECKSUM_HEADER
+    checksum_header: Option<String>,
+    /// Response header holding the detached signature of the supergraph (base64)
+    /// Default: X-Hive-Signature
+    signature_header: Option<String>,
+    /// Ed25519 public key (base64, raw or DER), the supergraph is rejected when its signature doesn't match
+    /// Environment variable: HIVE_CDN_PUBLIC_KEY
+    public_key: Option<String>,
+    /// Executable to run when a new supergraph is applied.
+    /// It receives the change as JSON on stdin and as HIVE_SUPERGRAPH_* environment variables.
+    /// Environment variable: HIVE_CDN_ON_CHANGE_COMMAND
+    on_change_command: Option<String>,
+    /// URL to POST the change to (JSON) when a new supergraph is applied
+    /// Environment variable: HIVE_CDN_ON_CHANGE_URL
+    on_change_url: Option<String>,
+    /// A timeout for the on-change command and request
+    /// Unit: seconds
+    /// Default: 10 (s)
+    on_change_timeout: Option<u64>,
+    /// Proxy, extra root certificates and client certificate of the CDN client
+    http: Option<HttpClientConfig>,
+}
+
+impl HiveRegistryConfig {
+    /// Fills the values missing in the config file with environment variables.
+    fn with_env(self) -> Result<Self, RegistryError> {
+        self.with_env_from(|name| env::var(name).ok())
+    }
+
+    /// Fills the values missing in the config file with the variables returned by `env`.
+    fn with_env_from(
+        mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, RegistryError> {
+        self.endpoints = match self.endpoints {
+            Some(endpoints) => Some(endpoints),
+            None => env("HIVE_CDN_ENDPOINT")
+                .map(|endpoints| endpoints.split(',').map(|e| e.to_string()).collect()),
         };
+        self.key = self.key.or_else(|| env("HIVE_CDN_KEY"));
+        self.contract = self.contract.or_else(|| env("HIVE_CDN_CONTRACT"));
+        self.artifact_path = self.artifact_path.or_else(|| env("HIVE_CDN_ARTIFACT_PATH"));
+        self.local_source = self
+            .local_source
+            .or_else(|| env("HIVE_REGISTRY_LOCAL_SOURCE"));
+        self.poll_interval = self
+            .poll_interval
+            .or(env_var(&env, "HIVE_CDN_POLL_INTERVAL")?);
+        self.accept_invalid_certs = match self.accept_invalid_certs {
+            Some(accept_invalid_certs) => Some(accept_invalid_certs),
+            None => env_bool(&env, "HIVE_CDN_ACCEPT_INVALID_CERTS")?,
+        };
+        self.schema_file_path = self
+            .schema_file_path
+            .or_else(|| env("HIVE_CDN_SCHEMA_FILE_PATH"));
+        self.connect_timeout = self
+            .connect_timeout
+            .or(env_var(&env, "HIVE_CDN_CONNECT_TIMEOUT")?);
+        self.request_timeout = self
+            .request_timeout
+            .or(env_var(&env, "HIVE_CDN_REQUEST_TIMEOUT")?);
+        self.max_backoff = self.max_backoff.or(env_var(&env, "HIVE_CDN_MAX_BACKOFF")?);
+        self.failure_threshold = self
+            .failure_threshold
+            .or(env_var(&env, "HIVE_CDN_FAILURE_THRESHOLD")?);
+        self.cache_dir = self.cache_dir.or_else(|| env("HIVE_CDN_CACHE_DIR"));
+        self.cache_max_age = self
+            .cache_max_age
+            .or(env_var(&env, "HIVE_CDN_CACHE_MAX_AGE")?);
+        self.startup_policy = self
+            .startup_policy
+            .or(env_var(&env, "HIVE_CDN_STARTUP_POLICY")?);
+        self.startup_timeout = self
+            .startup_timeout
+            .or(env_var(&env, "HIVE_CDN_STARTUP_TIMEOUT")?);
+        self.fallback_schema_path = self
+            .fallback_schema_path
+            .or_else(|| env("HIVE_CDN_FALLBACK_SCHEMA_PATH"));
+        self.schema_delivery = self
+            .schema_delivery
+            .or(env_var(&env, "HIVE_CDN_SCHEMA_DELIVERY")?);
+        self.usage_guard = self.usage_guard.or(env_var(&env, "HIVE_CDN_USAGE_GUARD")?);
+        self.usage_guard_window = self
+            .usage_guard_window
+            .or(env_var(&env, "HIVE_CDN_USAGE_GUARD_WINDOW")?);
+        self.artifacts = match self.artifacts {
+            Some(artifacts) => Some(artifacts),
+            None => match env("HIVE_CDN_ARTIFACTS") {
+                Some(value) => Some(
+                    value
+                        .split(',')
+                        .filter(|artifact| !artifact.trim().is_empty())
+                        .map(|artifact| {
+                            artifact.parse().map_err(|e: String| {
+                                RegistryError::InvalidConfig(format!(
+                                    "invalid value \"{}\" for HIVE_CDN_ARTIFACTS: {}",
+                                    value, e
+                                ))
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+                None => None,
+            },
+        };
+        self.pin = self.pin.or_else(|| env("HIVE_CDN_PIN"));
+        self.history_dir = self.history_dir.or_else(|| env("HIVE_CDN_HISTORY_DIR"));
+        self.history_size = self
+            .history_size
+            .or(env_var(&env, "HIVE_CDN_HISTORY_SIZE")?);
+        self.status_path = self.status_path.or_else(|| env("HIVE_CDN_STATUS_PATH"));
+        self.status_listen = self
+            .status_listen
+            .or(env_var(&env, "HIVE_CDN_STATUS_LISTEN")?);
+        self.max_schema_age = self
+            .max_schema_age
+            .or(env_var(&env, "HIVE_CDN_MAX_SCHEMA_AGE")?);
+        self.checksum_header = self
+            .checksum_header
+            .or_else(|| env("HIVE_CDN_CHECKSUM_HEADER"));
+        self.signature_header = self
+            .signature_header
+            .or_else(|| env("HIVE_CDN_SIGNATURE_HEADER"));
+        self.public_key = self.public_key.or_else(|| env("HIVE_CDN_PUBLIC_KEY"));
+        self.on_change_command = self
+            .on_change_command
+            .or_else(|| env("HIVE_CDN_ON_CHANGE_COMMAND"));
+        self.on_change_url = self.on_change_url.or_else(|| env("HIVE_CDN_ON_CHANGE_URL"));
+        self.on_change_timeout = self
+            .on_change_timeout
+            .or(env_var(&env, "HIVE_CDN_ON_CHANGE_TIMEOUT")?);
 
-        match registry.initial_supergraph() {
-            Ok(_) => {
-                registry
-                    .logger
-                    .info("Successfully fetched and saved supergraph from GraphQL Hive");
-            }
-            Err(e) => {
-                registry.logger.error(&e);
-                std::process::exit(1);
-            }
-        }
+        Ok(self)
+    }
+}
+
+/// Owns the poller started by `HiveRegistry::new`, and restarts it when the `hive.registry` section of `router.yaml` differs
+/// from the environment variables it was started with (the config is reloaded with the router's, `${env.X}` included).
+/// Exposes the subgraphs and metadata fetched from the CDN to other plugins through the `Context`,
+/// and the state of the registry through the status endpoint.
+struct RegistryPlugin {
+    status: Option<StatusEndpoint>,
+}
+
+#[derive(Clone)]
+struct StatusEndpoint {
+    listen: SocketAddr,
+    path: String,
+    max_schema_age: Option<Duration>,
+}
 
-        thread::spawn(move || loop {
-            thread::sleep(std::time::Duration::from_secs(poll_interval));
-            registry.poll()
+impl StatusEndpoint {
+    /// The status of the registry as JSON.
+    /// With `readiness`, the response is `503 Service Unavailable` when the registry is not ready.
+    fn respond(&self, req: router::Request, readiness: bool) -> Result<router::Response, BoxError> {
+        let status = registry_status::get().unwrap_or_default();
+        let ready = status.ready(self.max_schema_age);
+
+        let body = serde_json::json!({
+            "ready": ready.is_ok(),
+            "reason": ready.as_ref().err(),
+            "schema_age": status.schema_age().map(|age| age.as_secs()),
+            "status": status,
         });
+        let code = match (readiness, &ready) {
+            (true, Err(_)) => http::StatusCode::SERVICE_UNAVAILABLE,
+            _ => http::StatusCode::OK,
+        };
 
-        Ok(())
+        Ok(router::Response {
+            response: http::Response::builder()
+                .status(code)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(router::Body::from(body.to_string()))?,
+            context: req.context,
+        })
     }
+}
 
-    fn fetch_supergraph(&mut self, etag: Option<String>) -> Result<Option<String>, String> {
-        let client = reqwest::blocking::Client::builder()
-            .danger_accept_invalid_certs(self.accept_invalid_certs)
-            .build()
-            .map_err(|err| err.to_string())?;
-        let mut headers = reqwest::header::HeaderMap::new();
+#[async_trait::async_trait]
+impl Plugin for RegistryPlugin {
+    type Config = HiveRegistryConfig;
 
-        headers.insert(
-            reqwest::header::USER_AGENT,
-            reqwest::header::HeaderValue::from_str(
-                format!("hive-apollo-router/{}", COMMIT.unwrap_or_else(|| "local")).as_str(),
-            )
-            .unwrap(),
-        );
-        headers.insert("X-Hive-CDN-Key", self.key.parse().unwrap());
+    async fn new(init: PluginInit<HiveRegistryConfig>) -> Result<Self, BoxError> {
+        let config = init.config.with_env()?;
+        attach(config.clone(), &init.supergraph_sdl).await?;
+
+        Ok(RegistryPlugin {
+            status: config.status_path.map(|path| StatusEndpoint {
+                listen: config
+                    .status_listen
+                    .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 8088))),
+                path: format!("/{}", path.trim_matches('/')),
+                max_schema_age: config.max_schema_age.map(Duration::from_secs),
+            }),
+        })
+    }
+
+    fn web_endpoints(&self) -> MultiMap<ListenAddr, Endpoint> {
+        let mut endpoints = MultiMap::new();
 
-        if let Some(checksum) = etag {
-            headers.insert("If-None-Match", checksum.parse().unwrap());
+        let Some(status) = &self.status else {
+            return endpoints;
+        };
+
+        for readiness in [false, true] {
+            let path = match readiness {
+                true => format!("{}/ready", status.path),
+                false => status.path.clone(),
+            };
+            let status = status.clone();
+
+            endpoints.insert(
+                status.listen.into(),
+                Endpoint::from_router_service(
+                    path,
+                    service_fn(move |req: router::Request| {
+                        let response = status.respond(req, readiness);
+                        async move { response }
+                    })
+                    .boxed(),
+                ),
+            );
         }
 
-        let resp = client
-            .get(self.endpoint.as_str())
-            .headers(headers)
-            .send()
-            .map_err(|e| e.to_string())?;
+        endpoints
+    }
+
+    fn supergraph_service(&self, service: supergraph::BoxService) -> supergraph::BoxService {
+        ServiceBuilder::new()
+            .map_request(|req: supergraph::Request| {
+                if let Some(artifacts) = cdn_artifacts() {
+                    for (key, value) in artifacts.context_entries() {
+                        req.context.insert_json_value(*key, value.clone());
+                    }
+                }
+
+                req
+            })
+            .service(service)
+            .boxed()
+    }
+}
+
+// Register the hive.registry plugin
+pub fn register() {
+    register_plugin!("hive", "registry", RegistryPlugin);
+}
+
+/// Parses an environment variable, reporting the variable and the value when it's invalid.
+fn env_var<T>(env: impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, RegistryError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env(name) {
+        Some(value) => value.trim().parse().map(Some).map_err(|e| {
+            RegistryError::InvalidConfig(format!("invalid value \"{}\" for {}: {}", value, name, e))
+        }),
+        None => Ok(None),
+    }
+}
+
+fn env_bool(
+    env: impl Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<bool>, RegistryError> {
+    match env(name) {
+        Some(value) => match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "on" => Ok(Some(true)),
+            "0" | "false" | "off" | "" => Ok(Some(false)),
+            _ => Err(RegistryError::InvalidConfig(format!(
+                "invalid value \"{}\" for {}: expected true or false",
+                value, name
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+static COMMIT: Option<&'static str> = option_env!("GITHUB_SHA");
+
+impl HiveRegistry {
+    /// Fetches the initial supergraph and starts polling the CDN in a tokio task.
+    /// The router needs a supergraph before it creates its plugins, so the registry is configured with
+    /// `HIVE_CDN_*` environment variables here, and the `hive.registry` plugin takes the poller over
+    /// with the configuration of `router.yaml` once the router is started.
+    /// Returns `None` when GraphQL Hive is not used as the source of schema.
+    pub async fn new() -> Result<Option<HiveRegistryHandle>, RegistryError> {
+        let log_level = registry_logger::log_level();
+
+        // The registry starts before the router sets up its telemetry
+        if tracing::dispatcher::has_been_set() {
+            return Self::init(log_level).await;
+        }
+
+        let dispatch = registry_logger::startup_dispatch(log_level.clone().ok().flatten());
+        Self::init(log_level).with_subscriber(dispatch).await
+    }
+
+    async fn init(
+        log_level: Result<Option<LevelFilter>, String>,
+    ) -> Result<Option<HiveRegistryHandle>, RegistryError> {
+        if let Err(e) = log_level {
+            tracing::warn!("{}, using the router's log level", e);
+        }
+
+        let config = HiveRegistryConfig::default().with_env()?;
+        let Some((mut registry, schema)) = Self::configure(config.clone(), None)? else {
+            return Ok(None);
+        };
+        let _ = ROUTER_TARGET.set(registry.target.clone());
+
+        registry.start().await?;
+        registry.publish_status();
+
+        let delay = registry.poll_interval;
+        registry.spawn(config, delay);
+
+        Ok(Some(HiveRegistryHandle { schema }))
+    }
+
+    /// Builds the registry from the resolved configuration.
+    /// The supergraph is delivered to `target`, or to a new file or stream depending on `schema_delivery`,
+    /// returned next to the registry as the schema source of the router.
+    /// Returns `None` when GraphQL Hive is not used as the source of schema.
+    fn configure(
+        config: HiveRegistryConfig,
+        target: Option<SupergraphTarget>,
+    ) -> Result<Option<(Self, Option<SchemaSource>)>, RegistryError> {
+        // Resolve values
+        let endpoints: Vec<String> = config
+            .endpoints
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|endpoint| !endpoint.trim().is_empty())
+            .collect();
+        let key = config.key.unwrap_or_else(|| "".to_string());
+        let local = config
+            .local_source
+            .filter(|path| !path.trim().is_empty())
+            .map(LocalSource::new);
+        let non_empty = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+        let supergraph_artifact =
+            match (non_empty(config.contract), non_empty(config.artifact_path)) {
+                (None, None) => SupergraphArtifact::Default,
+                (Some(contract), None) => SupergraphArtifact::Contract(contract.trim().to_string()),
+                (None, Some(path)) => SupergraphArtifact::Path(path.trim().to_string()),
+                (Some(_), Some(_)) => {
+                    return Err(RegistryError::InvalidConfig(
+                        "contract and artifact_path can't be set together".to_string(),
+                    ))
+                }
+            };
+        let poll_interval: u64 = match (config.poll_interval, &local) {
+            (Some(value), _) => value,
+            (None, Some(_)) => 1,
+            (None, None) => 10,
+        };
+        let accept_invalid_certs = config.accept_invalid_certs.unwrap_or(false);
+        let connect_timeout = config.connect_timeout.unwrap_or(5);
+        let request_timeout = config.request_timeout.unwrap_or(15);
+        let max_backoff = config.max_backoff.unwrap_or(300);
+        let failure_threshold = config.failure_threshold.unwrap_or(3);
+        let startup_policy = config.startup_policy.unwrap_or(StartupPolicy::Fallback);
+        let startup_timeout = config.startup_timeout.unwrap_or(60);
+        let usage_guard = config.usage_guard.unwrap_or(UsageGuard::Off);
+        let usage_guard_window = Duration::from_secs(config.usage_guard_window.unwrap_or(3600));
+        let cache = config.cache_dir.map(|cache_dir| {
+            SupergraphCache::new(cache_dir, config.cache_max_age.map(Duration::from_secs))
+        });
+        let history = config.history_dir.map(|history_dir| {
+            SupergraphHistory::new(history_dir, config.history_size.unwrap_or(10))
+        });
+        let pin = config
+            .pin
+            .map(|pin| pin.trim().to_string())
+            .filter(|pin| !pin.is_empty());
+
+        let endpoints: Vec<CdnEndpoint> = match &local {
+            Some(local) => {
+                tracing::info!(
+                    path = %local.path().display(),
+                    "Reading the supergraph from a local source instead of GraphQL Hive CDN"
+                );
+                if !endpoints.is_empty() {
+                    tracing::warn!("GraphQL Hive CDN endpoints are ignored with a local source");
+                }
 
-        match resp.headers().get("etag") {
-            Some(checksum) => {
-                let etag = checksum.to_str().map_err(|e| e.to_string())?;
-                self.update_latest_etag(Some(etag.to_string()));
+                vec![CdnEndpoint::local(local.path())]
             }
             None => {
-                self.update_latest_etag(None);
+                // In case of an endpoint and an key being empty, we don't start the polling and skip the registry
+                if endpoints.is_empty() && key.is_empty() {
+                    tracing::info!("You're not using GraphQL Hive as the source of schema.");
+                    tracing::info!(
+                        "Reason: could not find HIVE_CDN_KEY and HIVE_CDN_ENDPOINT environment variables, nor HIVE_REGISTRY_LOCAL_SOURCE."
+                    );
+                    // A reload may have turned the registry off
+                    usage_window::disable();
+                    retain_cdn_artifacts(&[]);
+                    return Ok(None);
+                }
+
+                // Throw if endpoint is empty
+                if endpoints.is_empty() {
+                    return Err(RegistryError::MissingEndpoint);
+                }
+
+                let endpoints: Vec<CdnEndpoint> = endpoints
+                    .iter()
+                    .map(|endpoint| CdnEndpoint::new(endpoint, &supergraph_artifact))
+                    .collect();
+
+                // Throw if key is empty
+                if key.is_empty() {
+                    return Err(RegistryError::MissingKey);
+                }
+
+                endpoints
+            }
+        };
+
+        // The key is a secret, it's not repeated in the error
+        let mut key = HeaderValue::from_str(&key).map_err(|_| {
+            RegistryError::InvalidConfig("HIVE_CDN_KEY is not a valid header value".to_string())
+        })?;
+        key.set_sensitive(true);
+
+        let (target, schema) = match (
+            target,
+            config.schema_delivery.unwrap_or(SchemaDelivery::File),
+        ) {
+            (Some(target), _) => (target, None),
+            (None, SchemaDelivery::File) => {
+                let file_name = config.schema_file_path.unwrap_or(
+                    env::temp_dir()
+                        .join("supergraph-schema.graphql")
+                        .to_string_lossy()
+                        .to_string(),
+                );
+
+                // The router watches the file
+                #[allow(deprecated)]
+                let schema = SchemaSource::File {
+                    path: file_name.clone().into(),
+                    watch: true,
+                    delay: None,
+                };
+
+                (SupergraphTarget::File(file_name), Some(schema))
             }
+            (None, SchemaDelivery::Memory) => {
+                let (sender, receiver) = mpsc::unbounded();
+                (
+                    SupergraphTarget::Stream(sender),
+                    Some(SchemaSource::Stream(receiver.boxed())),
+                )
+            }
+        };
+
+        // Checksums, signatures and artifacts are served by the CDN only
+        let integrity = match &local {
+            Some(_) => None,
+            None => IntegrityCheck::new(
+                config.checksum_header,
+                config.signature_header,
+                config.public_key,
+            )
+            .map_err(RegistryError::InvalidConfig)?,
+        };
+        let artifacts = match &local {
+            Some(_) => vec![],
+            None => config.artifacts.unwrap_or_default(),
+        };
+
+        let hooks = ChangeHooks::new(
+            config.on_change_command,
+            config.on_change_url,
+            Duration::from_secs(config.on_change_timeout.unwrap_or(10)),
+        )
+        .map_err(RegistryError::InvalidConfig)?;
+
+        if accept_invalid_certs {
+            tracing::warn!(
+                "Accepting invalid certificates of GraphQL Hive CDN, do not use it in production"
+            );
         }
 
-        if resp.status().as_u16() == 304 {
-            return Ok(None);
+        let client = config
+            .http
+            .unwrap_or_default()
+            .with_env()
+            .apply(reqwest::Client::builder())
+            .map_err(RegistryError::HttpClient)?
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .connect_timeout(Duration::from_secs(connect_timeout))
+            .timeout(Duration::from_secs(request_timeout))
+            .build()
+            .map_err(|e| RegistryError::HttpClient(e.to_string()))?;
+
+        let registry = HiveRegistry {
+            endpoints,
+            active_endpoint: None,
+            key,
+            target,
+            supergraph: None,
+            supergraph_hash: None,
+            poll_interval: Duration::from_secs(poll_interval),
+            client,
+            backoff: Backoff::new(
+                Duration::from_secs(poll_interval),
+                Duration::from_secs(max_backoff),
+            ),
+            circuit: CircuitState::Closed,
+            failure_threshold,
+            consecutive_failures: 0,
+            retry_after: None,
+            cache,
+            startup_policy,
+            startup_timeout: Duration::from_secs(startup_timeout),
+            fallback_schema_path: config.fallback_schema_path,
+            usage_guard,
+            usage_guard_window,
+            artifacts,
+            pin,
+            history,
+            last_fetched_at: None,
+            last_error: None,
+            integrity,
+            hooks,
+            local,
+            rejected: None,
+        };
+
+        // Set again on every reload, the previous configuration may differ
+        match usage_guard {
+            UsageGuard::Off => usage_window::disable(),
+            _ => {
+                usage_window::enable(usage_guard_window);
+            }
         }
+        retain_cdn_artifacts(&registry.artifacts);
 
-        Ok(Some(resp.text().map_err(|e| e.to_string())?))
+        Ok(Some((registry, schema)))
     }
 
-    fn initial_supergraph(&mut self) -> Result<(), String> {
-        let mut file = std::fs::File::create(self.file_name.clone()).map_err(|e| e.to_string())?;
-        let resp = self.fetch_supergraph(None)?;
-
-        match resp {
-            Some(supergraph) => {
-                file.write_all(supergraph.as_bytes())
-                    .map_err(|e| e.to_string())?;
+    async fn start(&mut self) -> Result<(), RegistryError> {
+        if let Some(pin) = self.pin.clone() {
+            match self.restore_pinned(&pin).await {
+                Ok(true) => return Ok(()),
+                Ok(false) => tracing::info!(
+                    pin = %pin,
+                    "Supergraph is not in the history, fetching it from GraphQL Hive CDN"
+                ),
+                Err(e) => tracing::warn!(error = %e, "Failed to read the supergraph history"),
             }
-            None => {
-                return Err("Failed to fetch supergraph".to_string());
+        }
+
+        match self.startup_policy {
+            StartupPolicy::Fail => self
+                .initial_supergraph()
+                .await
+                .map_err(|e| RegistryError::InitialSupergraph(e.to_string()))?,
+            StartupPolicy::Wait => self.wait_for_supergraph().await?,
+            StartupPolicy::Fallback => {
+                if let Err(e) = self.initial_supergraph().await {
+                    tracing::error!(error = %e, "Failed to fetch the initial supergraph");
+
+                    return self.fallback().await.map_err(|fallback_error| {
+                        RegistryError::Fallback {
+                            error: e.to_string(),
+                            fallback_error,
+                        }
+                    });
+                }
             }
         }
 
+        tracing::info!(
+            endpoint = self.active_endpoint_url(),
+            etag = self.active_etag(),
+            hash = self.supergraph_hash.as_deref(),
+            "Successfully fetched and saved supergraph from GraphQL Hive"
+        );
+
+        if self.pinned() {
+            tracing::warn!(
+                hash = self.supergraph_hash.as_deref(),
+                "Pinned to supergraph, newer supergraphs are not applied"
+            );
+        }
+
         Ok(())
     }
 
-    fn update_latest_etag(&mut self, etag: Option<String>) {
-        self.etag = etag;
+    /// Applies the pinned supergraph from the history.
+    /// Returns `false` when there's no history or the pinned supergraph is not in it.
+    async fn restore_pinned(&mut self, pin: &str) -> Result<bool, String> {
+        let Some(history) = &self.history else {
+            return Ok(false);
+        };
+        let Some((entry, supergraph)) = history.find(pin).await? else {
+            return Ok(false);
+        };
+
+        validate_supergraph(&supergraph)?;
+        self.apply(supergraph).await?;
+        self.pin = Some(entry.hash.clone());
+
+        tracing::warn!(
+            hash = %entry.hash,
+            etag = entry.etag.as_deref(),
+            saved_at = entry.saved_at,
+            "Pinned to supergraph from the history, newer supergraphs are not applied"
+        );
+
+        Ok(true)
+    }
+
+    /// Makes the state of the poller available to the status endpoint
+    fn publish_status(&self) {
+        registry_status::publish(RegistryStatus {
+            last_successful_fetch: self.last_fetched_at,
+            last_error: self.last_error.clone(),
+            consecutive_failures: self.consecutive_failures,
+            circuit: self.circuit.to_string(),
+            endpoint: self
+                .active_endpoint
+                .map(|index| self.endpoints[index].url.clone()),
+            etag: self.active_etag().map(|etag| etag.to_string()),
+            supergraph_hash: self.supergraph_hash.clone(),
+            pinned: self.pinned(),
+        });
     }
 
-    fn poll(&mut self) {
-        match self.fetch_supergraph(self.etag.clone()) {
-            Ok(new_supergraph) => {
-                if let Some(new_supergraph) = new_supergraph {
-                    let current_file = std::fs::read_to_string(self.file_name.clone())
-                        .expect("Could not read file");
-                    let current_supergraph_hash = hash(current_file.as_bytes());
+    /// Whether the pinned supergraph is the one in use, nothing else is applied then
+    fn pinned(&self) -> bool {
+        self.pin.is_some() && self.pin == self.supergraph_hash
+    }
 
-                    let new_supergraph_hash = hash(new_supergraph.as_bytes());
+    /// Checks the supergraph against the pin.
+    /// A matching supergraph replaces the pin with its hash, so the pin no longer depends on the ETag.
+    fn check_pin(&mut self, supergraph_hash: &str) -> Result<(), String> {
+        let Some(pin) = &self.pin else {
+            return Ok(());
+        };
 
-                    if current_supergraph_hash != new_supergraph_hash {
-                        self.logger.info("New supergraph detected!");
-                        std::fs::write(self.file_name.clone(), new_supergraph)
-                            .expect("Could not write file");
-                    }
-                }
-            }
-            Err(e) => self.logger.error(&format!("{}", e)),
+        if !pin_matches(pin, supergraph_hash, self.active_etag()) {
+            return Err(format!(
+                "pinned to {}, GraphQL Hive CDN serves {} (etag: {})",
+                pin,
+                supergraph_hash,
+                self.active_etag().unwrap_or("none")
+            ));
         }
+
+        self.pin = Some(supergraph_hash.to_string());
+
+        Ok(())
     }
-}
 
-fn hash(bytes: &[u8]) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(bytes);
-    format!("{:X}", hasher.finalize())
+    /// Retries the initial fetch with backoff until `startup_timeout` runs out.
+    async fn wait_for_supergraph(&mut self) -> Result<(), RegistryError> {
+        let deadline = Instant::now() + self.startup_timeout;
+
+        loop {
+            match self.initial_supergraph().await {
+                Ok(_) => {
+                    self.record_success();
+                    return Ok(());
+                }
+                Err(e) => {
+                    let last_error = e.to_string();
+                    self.record_failure(e);
+
+                    let delay = self.next_delay();
+                    if Instant::now() + delay > deadline {
+                        return Err(RegistryError::StartupTimeout {
+                            timeout: self.startup_timeout,
+                            last_error,
+                        });
+                    }
+
+                    tracing::info!(
+                        delay = ?delay,
+                        "Waiting for GraphQL Hive CDN to become available"
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Starts from the cached supergraph, or from the fallback schema file when there's no usable cache.
+    async fn fallback(&mut self) -> Result<(), String> {
+        let cache_error = match self.restore_from_cache().await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let Some(fallback_schema_path) = self.fallback_schema_path.clone() else {
+            return Err(cache_error);
+        };
+
+        tracing::warn!(
+            error = %cache_error,
+            path = %fallback_schema_path,
+            "Failed to start from cached supergraph, using the fallback schema"
+        );
+
+        let supergraph = tokio::fs::read_to_string(&fallback_schema_path)
+            .await
+            .map_err(|e| format!("failed to read {}: {}", fallback_schema_path, e))?;
+        validate_supergraph(&supergraph)?;
+        self.apply(supergraph).await?;
+
+        Ok(())
+    }
+
+    /// Polls the CDN in a tokio task, the first poll happens after `delay`.
+    fn spawn(mut self, config: HiveRegistryConfig, delay: Duration) {
+        let (shutdown, mut shutdown_signal) = watch::channel(false);
+
+        let task = tokio::task::spawn(async move {
+            let mut delay = delay;
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = shutdown_signal.changed() => break,
+                }
+
+                // A poll in progress is never interrupted, so the supergraph file is not left half-written
+                self.poll().await;
+                self.publish_status();
+                delay = self.next_delay();
+            }
+
+            tracing::info!("Stopped polling GraphQL Hive CDN");
+        });
+
+        *poller() = Some(Poller {
+            config,
+            shutdown,
+            task,
+        });
+    }
+
+    /// Delay before the next poll.
+    /// Grows exponentially with consecutive failures and never undercuts the CDN's `Retry-After`.
+    fn next_delay(&self) -> Duration {
+        if self.consecutive_failures == 0 {
+            return self.poll_interval;
+        }
+
+        let delay = self.backoff.delay_with_jitter(self.consecutive_failures);
+
+        match self.retry_after {
+            Some(retry_after) => delay.max(retry_after),
+            None => delay,
+        }
+    }
+
+    fn set_circuit(&mut self, state: CircuitState) {
+        if self.circuit == state {
+            return;
+        }
+
+        match state {
+            CircuitState::Open => tracing::warn!(
+                circuit = %state,
+                previous = %self.circuit,
+                consecutive_failures = self.consecutive_failures,
+                "GraphQL Hive CDN circuit breaker is open"
+            ),
+            _ => tracing::info!(
+                circuit = %state,
+                previous = %self.circuit,
+                consecutive_failures = self.consecutive_failures,
+                "GraphQL Hive CDN circuit breaker is {}",
+                state
+            ),
+        }
+
+        self.circuit = state;
+    }
+
+    fn record_success(&mut self) {
+        if self.consecutive_failures > 0 {
+            tracing::info!(
+                consecutive_failures = self.consecutive_failures,
+                "Recovered after failed attempts to reach GraphQL Hive CDN"
+            );
+        }
+
+        self.consecutive_failures = 0;
+        self.retry_after = None;
+        self.last_error = None;
+        self.set_circuit(CircuitState::Closed);
+    }
+
+    fn record_failure(&mut self, error: FetchError) {
+        self.consecutive_failures += 1;
+        self.retry_after = error.retry_after;
+        self.last_error = Some(error.to_string());
+
+        tracing::error!(
+            attempt = self.consecutive_failures,
+            error = %error,
+            "Failed to fetch supergraph"
+        );
+
+        if self.circuit == CircuitState::HalfOpen
+            || self.consecutive_failures >= self.failure_threshold
+        {
+            self.set_circuit(CircuitState::Open);
+        }
+
+        tracing::debug!(delay = ?self.next_delay(), "Next poll scheduled");
+    }
+
+    /// Tries every endpoint in order and returns the first successful response.
+    /// When all of them fail, the errors are combined and the shortest `Retry-After` is kept.
+    async fn fetch_supergraph(&mut self) -> Result<Option<String>, FetchError> {
+        let mut errors = Vec::with_capacity(self.endpoints.len());
+        let mut retry_after: Option<Duration> = None;
+
+        for index in 0..self.endpoints.len() {
+            match self.fetch_from_endpoint(index).await {
+                Ok(supergraph) => {
+                    if self.active_endpoint != Some(index) {
+                        if let Some(previous) = self.active_endpoint {
+                            tracing::warn!(
+                                endpoint = %self.endpoints[index].url,
+                                previous = %self.endpoints[previous].url,
+                                "Switched GraphQL Hive CDN endpoint"
+                            );
+                        }
+                        self.active_endpoint = Some(index);
+                    }
+
+                    self.last_fetched_at = Some(now());
+                    return Ok(supergraph);
+                }
+                Err(e) => {
+                    if self.endpoints.len() > 1 {
+                        tracing::debug!(
+                            endpoint = %self.endpoints[index].url,
+                            error = %e,
+                            "GraphQL Hive CDN endpoint failed"
+                        );
+                    }
+
+                    retry_after = match (retry_after, e.retry_after) {
+                        (Some(a), Some(b)) => Some(a.min(b)),
+                        (a, b) => a.or(b),
+                    };
+                    errors.push(format!("{}: {}", self.endpoints[index].url, e));
+                }
+            }
+        }
+
+        Err(FetchError {
+            message: errors.join("; "),
+            retry_after,
+        })
+    }
+
+    async fn fetch_from_endpoint(&mut self, index: usize) -> Result<Option<String>, FetchError> {
+        let start = Instant::now();
+        let response = match self.local.as_mut() {
+            Some(local) => read_local(local).await,
+            None => {
+                self.request(
+                    &self.endpoints[index].url,
+                    self.endpoints[index].etag.as_deref(),
+                )
+                .await
+            }
+        };
+
+        let outcome = match &response {
+            Ok(CdnResponse { body: Some(_), .. }) => FetchOutcome::Ok,
+            Ok(CdnResponse { body: None, .. }) => FetchOutcome::NotModified,
+            Err(_) => FetchOutcome::Error,
+        };
+        let duration = start.elapsed();
+        metrics::registry_fetch(&self.endpoints[index].url, outcome, duration);
+        tracing::debug!(
+            endpoint = %self.endpoints[index].url,
+            outcome = ?outcome,
+            duration = ?duration,
+            "Fetched supergraph"
+        );
+
+        let response = response?;
+
+        if let (Some(integrity), Some(body)) = (&self.integrity, &response.body) {
+            integrity.verify(body, &response.headers).map_err(|e| {
+                FetchError::new(format!(
+                    "Rejected supergraph, integrity check failed: {}",
+                    e
+                ))
+            })?;
+        }
+
+        // Some CDNs and proxies answer 304 without an ETag, the one sent is still valid
+        if response.body.is_some() || response.etag.is_some() {
+            self.endpoints[index].etag = response.etag;
+        }
+
+        Ok(response.body)
+    }
+
+    /// Conditional GET of a CDN artifact, `etag` is sent as `If-None-Match`.
+    async fn request(&self, url: &str, etag: Option<&str>) -> Result<CdnResponse, FetchError> {
+        let mut headers = reqwest::header::HeaderMap::new();
+
+        headers.insert(
+            reqwest::header::USER_AGENT,
+            reqwest::header::HeaderValue::from_str(
+                format!("hive-apollo-router/{}", COMMIT.unwrap_or_else(|| "local")).as_str(),
+            )
+            .unwrap(),
+        );
+        headers.insert("X-Hive-CDN-Key", self.key.clone());
+
+        // An ETag that isn't a valid header value is never matched, the request is sent unconditionally
+        if let Some(Ok(checksum)) = etag.map(HeaderValue::from_str) {
+            headers.insert("If-None-Match", checksum);
+        }
+
+        let resp = self
+            .client
+            .get(url)
+            .headers(headers)
+            .send()
+            .await
+            .map_err(FetchError::new)?;
+
+        if resp.status().as_u16() != 304 && !resp.status().is_success() {
+            return Err(FetchError {
+                message: format!(
+                    "({}) unexpected response from GraphQL Hive CDN",
+                    resp.status()
+                ),
+                retry_after: retry_after(resp.headers()),
+            });
+        }
+
+        let etag = match resp.headers().get("etag") {
+            Some(checksum) => Some(checksum.to_str().map_err(FetchError::new)?.to_string()),
+            None => None,
+        };
+
+        if resp.status().as_u16() == 304 {
+            return Ok(CdnResponse {
+                etag,
+                body: None,
+                headers: resp.headers().clone(),
+            });
+        }
+
+        let headers = resp.headers().clone();
+
+        Ok(CdnResponse {
+            etag,
+            body: Some(resp.text().await.map_err(FetchError::new)?),
+            headers,
+        })
+    }
+
+    /// Fetches the configured artifacts from the endpoint that served the supergraph.
+    /// Failures are logged and don't count towards the circuit breaker, the previous version is kept.
+    async fn refresh_artifacts(&mut self) {
+        let Some(index) = self.active_endpoint else {
+            return;
+        };
+
+        for artifact in self.artifacts.clone() {
+            let url = self.endpoints[index].artifact_url(artifact);
+            let etag = self.endpoints[index].artifact_etags.get(&artifact).cloned();
+
+            let response = match self.request(&url, etag.as_deref()).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::warn!(url = %url, error = %e, "Failed to fetch artifact");
+                    continue;
+                }
+            };
+
+            let Some(body) = response.body else {
+                continue;
+            };
+
+            match update_cdn_artifact(artifact, body) {
+                Ok(_) => match response.etag {
+                    Some(etag) => {
+                        self.endpoints[index].artifact_etags.insert(artifact, etag);
+                    }
+                    None => {
+                        self.endpoints[index].artifact_etags.remove(&artifact);
+                    }
+                },
+                Err(e) => {
+                    tracing::warn!(url = %url, error = %e, "Rejected artifact");
+                    self.endpoints[index].artifact_etags.remove(&artifact);
+                }
+            }
+        }
+    }
+
+    async fn initial_supergraph(&mut self) -> Result<(), FetchError> {
+        let resp = self.fetch_supergraph().await?;
+
+        match resp {
+            Some(supergraph) => {
+                validate_supergraph(&supergraph)
+                    .map_err(|e| FetchError::new(format!("Rejected supergraph: {}", e)))?;
+                if let Err(e) = self.check_pin(&hash(supergraph.as_bytes())) {
+                    // Fetch it again on the next attempt, the CDN may serve the pinned version by then
+                    if let Some(index) = self.active_endpoint {
+                        self.endpoints[index].etag = None;
+                    }
+                    return Err(FetchError::new(e));
+                }
+                self.apply(supergraph.clone())
+                    .await
+                    .map_err(FetchError::new)?;
+                metrics::registry_schema_change("applied");
+                self.persist(&supergraph).await;
+                self.refresh_artifacts().await;
+            }
+            None => {
+                return Err(FetchError::new("Failed to fetch supergraph"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Saves an applied supergraph to the cache and the history.
+    /// A local source is its own history, nothing is saved.
+    async fn persist(&self, supergraph: &str) {
+        if self.local.is_some() {
+            return;
+        }
+
+        if let Some(history) = &self.history {
+            if let Err(e) = history
+                .save(supergraph, self.active_etag().map(|etag| etag.to_string()))
+                .await
+            {
+                tracing::warn!(error = %e, "Failed to add the supergraph to the history");
+            }
+        }
+
+        let Some(cache) = &self.cache else {
+            return;
+        };
+
+        let endpoint = self.active_endpoint.map(|index| &self.endpoints[index]);
+
+        if let Err(e) = cache
+            .save(
+                supergraph,
+                endpoint.and_then(|endpoint| endpoint.etag.clone()),
+                endpoint.map(|endpoint| endpoint.url.clone()),
+            )
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to cache the supergraph");
+        }
+    }
+
+    /// The CDN still serves the applied supergraph, so the cached copy is as fresh as it gets
+    async fn confirm_cached(&self) {
+        if self.local.is_some() {
+            return;
+        }
+
+        let (Some(cache), Some(supergraph_hash)) = (&self.cache, &self.supergraph_hash) else {
+            return;
+        };
+
+        if let Err(e) = cache.confirm(supergraph_hash).await {
+            tracing::warn!(error = %e, "Failed to refresh the cached supergraph");
+        }
+    }
+
+    /// Writes the last known good supergraph and resumes conditional polling from its ETag.
+    async fn restore_from_cache(&mut self) -> Result<(), String> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| "HIVE_CDN_CACHE_DIR is not set".to_string())?;
+        let cached = cache.load().await?;
+
+        if let Some(pin) = &self.pin {
+            if !pin_matches(pin, &cached.metadata.hash, cached.metadata.etag.as_deref()) {
+                return Err(format!(
+                    "cached supergraph {} is not the pinned one ({})",
+                    cached.metadata.hash, pin
+                ));
+            }
+        }
+
+        validate_supergraph(&cached.supergraph)?;
+        self.apply(cached.supergraph).await?;
+
+        if let Some(index) = self
+            .endpoints
+            .iter()
+            .position(|endpoint| Some(&endpoint.url) == cached.metadata.endpoint.as_ref())
+        {
+            self.endpoints[index].etag = cached.metadata.etag.clone();
+            self.active_endpoint = Some(index);
+        }
+        self.last_fetched_at = Some(cached.metadata.saved_at);
+
+        tracing::warn!(
+            hash = %cached.metadata.hash,
+            etag = cached.metadata.etag.as_deref(),
+            endpoint = cached.metadata.endpoint.as_deref(),
+            saved_at = cached.metadata.saved_at,
+            "Started from cached supergraph"
+        );
+
+        Ok(())
+    }
+
+    /// Hands the supergraph over to the router
+    async fn apply(&mut self, supergraph: String) -> Result<(), String> {
+        match &self.target {
+            SupergraphTarget::File(file_name) => write_atomically(file_name, &supergraph)
+                .await
+                .map_err(|e| e.to_string())?,
+            SupergraphTarget::Stream(sender) => sender
+                .unbounded_send(supergraph.clone())
+                .map_err(|_| "the router no longer receives supergraphs".to_string())?,
+        }
+
+        self.supergraph_hash = Some(hash(supergraph.as_bytes()));
+        self.supergraph = Some(supergraph);
+
+        Ok(())
+    }
+
+    /// Runs the on-change hooks in the background, the update never waits for them
+    fn notify_change(&self, previous_hash: Option<String>) {
+        let (Some(hooks), Some(hash)) = (&self.hooks, &self.supergraph_hash) else {
+            return;
+        };
+
+        hooks.notify(SupergraphChange {
+            previous_hash,
+            hash: hash.clone(),
+            etag: self.active_etag().map(|etag| etag.to_string()),
+            file_path: match &self.target {
+                SupergraphTarget::File(file_name) => Some(file_name.clone()),
+                SupergraphTarget::Stream(_) => None,
+            },
+        });
+    }
+
+    /// Logs why a supergraph was not applied, once per supergraph.
+    /// The ETag of the endpoint that served it is kept, so the same supergraph is not downloaded on every poll.
+    /// `held` is the supergraph held back by the usage guard, to check it again later.
+    fn reject(&mut self, supergraph_hash: &str, reason: &str, held: Option<String>) {
+        if self.is_rejected(supergraph_hash) {
+            tracing::debug!(
+                hash = supergraph_hash,
+                reason = reason,
+                "Supergraph is still rejected"
+            );
+        } else {
+            metrics::registry_schema_change("rejected");
+            tracing::error!(
+                hash = supergraph_hash,
+                endpoint = self.active_endpoint_url(),
+                etag = self.active_etag(),
+                reason = reason,
+                "Rejected supergraph"
+            );
+        }
+
+        self.rejected = Some(RejectedSupergraph {
+            hash: supergraph_hash.to_string(),
+            held,
+        });
+    }
+
+    fn is_rejected(&self, supergraph_hash: &str) -> bool {
+        self.rejected
+            .as_ref()
+            .is_some_and(|rejected| rejected.hash == supergraph_hash)
+    }
+
+    /// Compares the new supergraph with the current one, and logs a summary of what changed
+    fn log_changes(&self, new_supergraph: &str, summary: bool) -> Option<SupergraphDiff> {
+        let current_supergraph = self.supergraph.as_ref()?;
+
+        match diff(current_supergraph, new_supergraph) {
+            Ok(changes) => {
+                if summary {
+                    tracing::info!(changes = %changes, "Supergraph changes");
+                }
+                Some(changes)
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to compare supergraphs");
+                None
+            }
+        }
+    }
+
+    /// Looks for removed types, fields, enum values and arguments used by recent traffic.
+    /// Returns an error when the supergraph should be held back.
+    fn check_usage(&self, changes: &SupergraphDiff) -> Result<(), String> {
+        if self.usage_guard == UsageGuard::Off {
+            return Ok(());
+        }
+
+        let Some(window) = usage_window::get() else {
+            return Ok(());
+        };
+
+        let removed_types: HashSet<&str> =
+            changes.types_removed.iter().map(|t| t.as_str()).collect();
+        // Fields, enum values and arguments. The arguments of a removed field are matched through the field
+        let removed_members: HashSet<&str> = changes
+            .fields_removed
+            .iter()
+            .chain(&changes.enum_values_removed)
+            .chain(&changes.arguments_removed)
+            .map(|c| c.as_str())
+            .collect();
+
+        if removed_types.is_empty() && removed_members.is_empty() {
+            return Ok(());
+        }
+
+        let used = window.used(|coordinate| {
+            let mut parts = coordinate.split('.');
+            let type_name = parts.next().unwrap_or_default();
+
+            removed_types.contains(type_name)
+                || removed_members.contains(coordinate)
+                || parts
+                    .next()
+                    .map(|member| {
+                        removed_members.contains(format!("{}.{}", type_name, member).as_str())
+                    })
+                    .unwrap_or(false)
+        });
+
+        if used.is_empty() {
+            return Ok(());
+        }
+
+        let usage = used
+            .iter()
+            .map(|(coordinate, operations)| {
+                format!(
+                    "{} used by {}",
+                    coordinate,
+                    operations
+                        .iter()
+                        .map(|operation| operation.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        match self.usage_guard {
+            UsageGuard::Hold => Err(format!(
+                "it removes {} schema coordinate(s) used within the last {}s: {}",
+                used.len(),
+                self.usage_guard_window.as_secs(),
+                usage
+            )),
+            _ => {
+                tracing::warn!(
+                    coordinates = used.len(),
+                    window = ?self.usage_guard_window,
+                    usage = %usage,
+                    "Applying a supergraph removing schema coordinates used by recent traffic"
+                );
+                Ok(())
+            }
+        }
+    }
+
+    fn active_etag(&self) -> Option<&str> {
+        self.active_endpoint
+            .and_then(|index| self.endpoints[index].etag.as_deref())
+    }
+
+    fn active_endpoint_url(&self) -> &str {
+        self.active_endpoint
+            .map(|index| self.endpoints[index].url.as_str())
+            .unwrap_or("none")
+    }
+
+    async fn poll(&mut self) {
+        if self.pinned() {
+            return;
+        }
+
+        if self.circuit == CircuitState::Open {
+            self.set_circuit(CircuitState::HalfOpen);
+        }
+
+        match self.fetch_supergraph().await {
+            Ok(new_supergraph) => {
+                self.record_success();
+                self.refresh_artifacts().await;
+
+                let new_supergraph = match (new_supergraph, &self.rejected) {
+                    (Some(new_supergraph), _) => new_supergraph,
+                    // Unchanged, but the usage may have dropped since the supergraph was held back
+                    (
+                        None,
+                        Some(RejectedSupergraph {
+                            held: Some(held), ..
+                        }),
+                    ) => held.clone(),
+                    // The CDN still serves the rejected supergraph
+                    (None, Some(_)) => return,
+                    (None, None) => {
+                        self.confirm_cached().await;
+                        return;
+                    }
+                };
+                let new_supergraph_hash = hash(new_supergraph.as_bytes());
+
+                if self.supergraph_hash.as_ref() == Some(&new_supergraph_hash) {
+                    self.rejected = None;
+                    self.confirm_cached().await;
+                    return;
+                }
+
+                if let Err(e) = validate_supergraph(&new_supergraph) {
+                    self.reject(&new_supergraph_hash, &e, None);
+                    return;
+                }
+
+                if let Err(e) = self.check_pin(&new_supergraph_hash) {
+                    tracing::info!(reason = %e, "Not applying supergraph");
+                    return;
+                }
+
+                let checked_before = self.is_rejected(&new_supergraph_hash);
+                if !checked_before {
+                    tracing::info!(
+                        endpoint = self.active_endpoint_url(),
+                        etag = self.active_etag(),
+                        previous_hash = self.supergraph_hash.as_deref(),
+                        hash = %new_supergraph_hash,
+                        "New supergraph detected!"
+                    );
+                }
+                if let Some(changes) = self.log_changes(&new_supergraph, !checked_before) {
+                    if let Err(reason) = self.check_usage(&changes) {
+                        self.reject(&new_supergraph_hash, &reason, Some(new_supergraph));
+                        return;
+                    }
+                }
+
+                let previous_hash = self.supergraph_hash.clone();
+
+                match self.apply(new_supergraph.clone()).await {
+                    Ok(_) => {
+                        metrics::registry_schema_change("applied");
+                        self.rejected = None;
+                        self.persist(&new_supergraph).await;
+                        self.notify_change(previous_hash);
+                    }
+                    Err(e) => self.reject(&new_supergraph_hash, &e, None),
+                }
+            }
+            Err(e) => self.record_failure(e),
+        }
+    }
+}
+
+/// Reads the supergraph from disk, as if the CDN served it.
+/// The file name is the version (ETag), an unchanged file is not modified.
+async fn read_local(local: &mut LocalSource) -> Result<CdnResponse, FetchError> {
+    let read = local.read().await.map_err(FetchError::new)?;
+
+    Ok(CdnResponse {
+        etag: Some(read.version),
+        body: read.supergraph,
+        headers: reqwest::header::HeaderMap::new(),
+    })
+}
+
+/// Makes sure the SDL parses and is a federation supergraph before the router sees it.
+fn validate_supergraph(supergraph: &str) -> Result<(), String> {
+    let document = parse_schema::<&str>(supergraph).map_err(|e| e.to_string())?;
+
+    let has_join_definitions = document.definitions.iter().any(|definition| {
+        let name = match definition {
+            Definition::TypeDefinition(TypeDefinition::Scalar(t)) => t.name,
+            Definition::TypeDefinition(TypeDefinition::Object(t)) => t.name,
+            Definition::TypeDefinition(TypeDefinition::Interface(t)) => t.name,
+            Definition::TypeDefinition(TypeDefinition::Union(t)) => t.name,
+            Definition::TypeDefinition(TypeDefinition::Enum(t)) => t.name,
+            Definition::TypeDefinition(TypeDefinition::InputObject(t)) => t.name,
+            Definition::DirectiveDefinition(d) => d.name,
+            _ => return false,
+        };
+
+        name.starts_with("join__")
+    });
+
+    if !has_join_definitions {
+        return Err("no join__ definitions found, it's not a federation supergraph".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        env_var, reads_the_supergraph_file, validate_supergraph, CdnArtifact, CdnEndpoint,
+        CircuitState, HiveRegistry, HiveRegistryConfig, RegistryError, StartupPolicy,
+        SupergraphArtifact, SupergraphTarget, UsageGuard,
+    };
+    use crate::backoff::Backoff;
+    use crate::fs_util::hash;
+    use crate::registry_artifacts::cdn_artifacts;
+    use crate::registry_cache::SupergraphCache;
+    use crate::registry_diff::SupergraphDiff;
+    use crate::registry_history::SupergraphHistory;
+    use crate::registry_hooks::ChangeHooks;
+    use crate::registry_integrity::IntegrityCheck;
+    use crate::registry_local::LocalSource;
+    use crate::test_support::StandIn;
+    use crate::usage_window::{self, OperationRef};
+    use futures::StreamExt;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+
+    /// A local stand-in for the CDN, answering each connection with the next canned response.
+    /// `None` accepts the connection and never answers.
+    async fn serve(responses: Vec<Option<&'static str>>) -> String {
+        StandIn::serve(responses).await.url("/supergraph")
+    }
+
+    fn registry(dir: &Path, endpoints: Vec<String>, request_timeout: Duration) -> HiveRegistry {
+        let file_name = dir.join("supergraph.graphql").to_string_lossy().to_string();
+        std::fs::write(&file_name, supergraph("a")).unwrap();
+
+        HiveRegistry {
+            endpoints: endpoints
+                .iter()
+                .map(|e| CdnEndpoint::new(e, &SupergraphArtifact::Default))
+                .collect(),
+            active_endpoint: None,
+            key: HeaderValue::from_static("key"),
+            target: SupergraphTarget::File(file_name),
+            supergraph: Some(supergraph("a")),
+            supergraph_hash: Some(hash(supergraph("a").as_bytes())),
+            poll_interval: Duration::from_secs(10),
+            client: reqwest::Client::builder()
+                .timeout(request_timeout)
+                .build()
+                .unwrap(),
+            backoff: Backoff::new(Duration::from_secs(10), Duration::from_secs(300)),
+            circuit: CircuitState::Closed,
+            failure_threshold: 2,
+            consecutive_failures: 0,
+            retry_after: None,
+            cache: None,
+            startup_policy: StartupPolicy::Fail,
+            startup_timeout: Duration::from_secs(60),
+            fallback_schema_path: None,
+            usage_guard: UsageGuard::Off,
+            usage_guard_window: Duration::from_secs(3600),
+            artifacts: vec![],
+            pin: None,
+            history: None,
+            last_fetched_at: None,
+            last_error: None,
+            integrity: None,
+            hooks: None,
+            local: None,
+            rejected: None,
+        }
+    }
+
+    fn schema_file(registry: &HiveRegistry) -> String {
+        match &registry.target {
+            SupergraphTarget::File(file_name) => std::fs::read_to_string(file_name).unwrap(),
+            SupergraphTarget::Stream(_) => panic!("the supergraph is not written to a file"),
+        }
+    }
+
+    fn supergraph(field: &str) -> String {
+        format!(
+            "directive @join__graph(name: String!, url: String!) on ENUM_VALUE\n\
+             enum join__Graph {{ A @join__graph(name: \"a\", url: \"http://a\") }}\n\
+             type Query {{ {}: String }}\n",
+            field
+        )
+    }
+
+    fn ok_response(body: &str, etag: &str) -> &'static str {
+        Box::leak(
+            format!(
+                "HTTP/1.1 200 OK\r\netag: \"{}\"\r\ncontent-length: {}\r\n\r\n{}",
+                etag,
+                body.len(),
+                body
+            )
+            .into_boxed_str(),
+        )
+    }
+
+    const SERVER_ERROR: &str = "HTTP/1.1 500 Internal Server Error\r\ncontent-length: 0\r\n\r\n";
+    const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\netag: \"abc\"\r\n\r\n";
+
+    #[test]
+    fn builds_the_supergraph_url() {
+        let cdn = "https://cdn.graphql-hive.com/artifacts/v1/target";
+        let url = |endpoint: &str, artifact: SupergraphArtifact| {
+            CdnEndpoint::new(endpoint, &artifact).url
+        };
+
+        assert_eq!(
+            url(cdn, SupergraphArtifact::Default),
+            format!("{}/supergraph", cdn)
+        );
+        assert_eq!(
+            url(&format!("{}/supergraph", cdn), SupergraphArtifact::Default),
+            format!("{}/supergraph", cdn)
+        );
+        assert_eq!(
+            url(
+                &format!("{}/", cdn),
+                SupergraphArtifact::Contract("public".to_string())
+            ),
+            format!("{}/contracts/public/supergraph", cdn)
+        );
+        assert_eq!(
+            url(
+                cdn,
+                SupergraphArtifact::Path("/contracts/public/supergraph".to_string())
+            ),
+            format!("{}/contracts/public/supergraph", cdn)
+        );
+        // the endpoint of the default supergraph is accepted as is
+        assert_eq!(
+            url(
+                &format!("{}/supergraph/", cdn),
+                SupergraphArtifact::Contract("public".to_string())
+            ),
+            format!("{}/contracts/public/supergraph", cdn)
+        );
+        assert_eq!(
+            url(
+                &format!("{}/supergraph", cdn),
+                SupergraphArtifact::Path("contracts/public/supergraph".to_string())
+            ),
+            format!("{}/contracts/public/supergraph", cdn)
+        );
+        assert_eq!(
+            url(
+                cdn,
+                SupergraphArtifact::Contract("mobile app/v2".to_string())
+            ),
+            format!("{}/contracts/mobile%20app%2Fv2/supergraph", cdn)
+        );
+
+        let contract = CdnEndpoint::new(cdn, &SupergraphArtifact::Contract("public".to_string()));
+        assert_eq!(
+            contract.artifact_url(CdnArtifact::Sdl),
+            format!("{}/contracts/public/sdl", cdn)
+        );
+    }
+
+    #[tokio::test]
+    async fn opens_the_circuit_after_consecutive_failures() {
+        let endpoint = serve(vec![
+            Some(SERVER_ERROR),
+            Some(SERVER_ERROR),
+            Some(SERVER_ERROR),
+            Some(NOT_MODIFIED),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+
+        registry.poll().await;
+        assert_eq!(registry.circuit, CircuitState::Closed);
+        assert_eq!(registry.consecutive_failures, 1);
+
+        registry.poll().await;
+        assert_eq!(registry.circuit, CircuitState::Open);
+        assert!(registry.next_delay() >= Duration::from_secs(10));
+
+        registry.poll().await;
+        assert_eq!(registry.circuit, CircuitState::Open);
+        assert_eq!(registry.consecutive_failures, 3);
+        assert!(registry.next_delay() >= Duration::from_secs(20));
+
+        registry.poll().await;
+        assert_eq!(registry.circuit, CircuitState::Closed);
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(registry.next_delay(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn honors_retry_after() {
+        let endpoint = serve(vec![Some(
+            "HTTP/1.1 503 Service Unavailable\r\nretry-after: 120\r\ncontent-length: 0\r\n\r\n",
+        )])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+
+        registry.poll().await;
+        assert_eq!(registry.retry_after, Some(Duration::from_secs(120)));
+        assert!(registry.next_delay() >= Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn times_out_on_a_hung_connection() {
+        let endpoint = serve(vec![None]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_millis(200));
+
+        let start = Instant::now();
+        registry.poll().await;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(registry.consecutive_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_endpoint() {
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let mirror = serve(vec![
+            Some(ok_response(&supergraph("b"), "v2")),
+            Some(NOT_MODIFIED),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(
+            dir.path(),
+            vec![unreachable, mirror.clone()],
+            Duration::from_secs(5),
+        );
+
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(registry.active_endpoint, Some(1));
+        assert_eq!(registry.active_endpoint_url(), mirror);
+        assert_eq!(registry.endpoints[0].etag, None);
+        assert_eq!(registry.endpoints[1].etag, Some("\"v2\"".to_string()));
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn fetches_artifacts_next_to_the_supergraph() {
+        let services = r#"[{"name":"a","url":"http://a","sdl":"type Query { a: String }"}]"#;
+        let endpoint = serve(vec![
+            Some(ok_response(&supergraph("b"), "v2")),
+            Some(ok_response(services, "s1")),
+            Some(NOT_MODIFIED),
+            Some(SERVER_ERROR),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint.clone()], Duration::from_secs(5));
+        registry.artifacts = vec![CdnArtifact::Services];
+
+        assert_eq!(
+            registry.endpoints[0].artifact_url(CdnArtifact::Services),
+            endpoint.replace("/supergraph", "/services")
+        );
+
+        registry.poll().await;
+        assert_eq!(
+            registry.endpoints[0].artifact_etags[&CdnArtifact::Services],
+            "\"s1\""
+        );
+        let subgraphs = cdn_artifacts().unwrap().subgraphs.clone().unwrap();
+        assert_eq!(subgraphs[0].name, "a");
+        assert_eq!(subgraphs[0].url.as_deref(), Some("http://a"));
+
+        // a failing artifact keeps the previous version and doesn't affect the supergraph
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(cdn_artifacts().unwrap().subgraphs, Some(subgraphs));
+    }
+
+    #[tokio::test]
+    async fn rolls_back_to_a_pinned_supergraph_from_the_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history_dir = dir.path().join("history");
+        let endpoint = serve(vec![
+            Some(ok_response(&supergraph("b"), "v2")),
+            Some(ok_response(&supergraph("c"), "v3")),
+        ])
+        .await;
+        let mut latest = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        latest.history = Some(SupergraphHistory::new(&history_dir, 10));
+
+        latest.poll().await;
+        latest.poll().await;
+        assert_eq!(schema_file(&latest), supergraph("c"));
+
+        // The CDN is gone, the pinned supergraph comes from the history
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let mut registry = registry(dir.path(), vec![unreachable], Duration::from_secs(5));
+        registry.history = Some(SupergraphHistory::new(&history_dir, 10));
+        registry.pin = Some("v2".to_string());
+
+        registry.start().await.unwrap();
+        assert_eq!(schema_file(&registry), supergraph("b"));
+        assert!(registry.pinned());
+
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn does_not_apply_supergraphs_other_than_the_pinned_one() {
+        let endpoint = serve(vec![Some(ok_response(&supergraph("b"), "v2"))]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.pin = Some("v1".to_string());
+
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("a"));
+        assert!(!registry.pinned());
+    }
+
+    #[test]
+    fn validates_supergraph() {
+        assert!(validate_supergraph(&supergraph("a")).is_ok());
+        assert!(validate_supergraph("type Query { a: String }").is_err());
+        assert!(validate_supergraph(&supergraph("a")[..60]).is_err());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_etag_on_not_modified_without_one() {
+        let endpoint = serve(vec![
+            Some(ok_response(&supergraph("b"), "v2")),
+            Some("HTTP/1.1 304 Not Modified\r\n\r\n"),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+
+        registry.poll().await;
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(registry.endpoints[0].etag.as_deref(), Some("\"v2\""));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_current_supergraph_when_the_new_one_is_invalid() {
+        let endpoint = serve(vec![
+            Some(ok_response("type Query { b: Str", "v2")),
+            Some(ok_response(&supergraph("b"), "v2")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("a"));
+        // the ETag of the rejected supergraph is kept, it's not downloaded again
+        assert_eq!(registry.endpoints[0].etag, Some("\"v2\"".to_string()));
+
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn rejects_supergraphs_failing_the_integrity_check() {
+        let with_checksum = |body: String, checksum: String| -> &'static str {
+            Box::leak(
+                format!(
+                    "HTTP/1.1 200 OK\r\netag: \"v2\"\r\nx-checksum: {}\r\ncontent-length: {}\r\n\r\n{}",
+                    checksum,
+                    body.len(),
+                    body
+                )
+                .into_boxed_str(),
+            )
+        };
+        let endpoint = serve(vec![
+            Some(with_checksum(
+                supergraph("b"),
+                hash(supergraph("c").as_bytes()),
+            )),
+            Some(with_checksum(
+                supergraph("b"),
+                hash(supergraph("b").as_bytes()),
+            )),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.integrity =
+            IntegrityCheck::new(Some("x-checksum".to_string()), None, None).unwrap();
+
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 1);
+        assert_eq!(registry.endpoints[0].etag, None);
+        assert_eq!(schema_file(&registry), supergraph("a"));
+
+        registry.poll().await;
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn applies_the_supergraph_when_a_hook_fails() {
+        let endpoint = serve(vec![Some(ok_response(&supergraph("b"), "v2"))]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.hooks = ChangeHooks::new(
+            Some("/non-existent/hook".to_string()),
+            Some("http://127.0.0.1:1/hook".to_string()),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("b"));
+        assert_eq!(registry.consecutive_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn reads_the_supergraph_from_a_local_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("v1.graphql"), supergraph("a")).unwrap();
+
+        let cache_dir = dir.path().join("cache");
+        let mut registry = registry(dir.path(), vec![], Duration::from_secs(5));
+        registry.local = Some(LocalSource::new(&source));
+        registry.endpoints = vec![CdnEndpoint::local(&source)];
+        registry.cache = Some(SupergraphCache::new(cache_dir.clone(), None));
+
+        registry.poll().await;
+        assert_eq!(registry.active_etag(), Some("v1.graphql"));
+        assert_eq!(schema_file(&registry), supergraph("a"));
+        // local supergraphs are never cached
+        assert!(!cache_dir.exists());
+
+        // goes through the same validation as the CDN
+        std::fs::write(source.join("v2.graphql"), "type Query { b: Str").unwrap();
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("a"));
+
+        std::fs::write(source.join("v10.graphql"), supergraph("b")).unwrap();
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("b"));
+        assert_eq!(registry.active_etag(), Some("v10.graphql"));
+    }
+
+    #[tokio::test]
+    async fn starts_from_the_cache_when_the_cdn_is_down() {
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            format!("http://{}/supergraph", listener.local_addr().unwrap())
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SupergraphCache::new(dir.path().join("cache"), None);
+        cache
+            .save(
+                &supergraph("cached"),
+                Some("\"v1\"".to_string()),
+                Some(unreachable.clone()),
+            )
+            .await
+            .unwrap();
+
+        let mut registry = registry(dir.path(), vec![unreachable], Duration::from_secs(5));
+        registry.cache = Some(cache);
+
+        assert!(registry.initial_supergraph().await.is_err());
+        registry.restore_from_cache().await.unwrap();
+
+        assert_eq!(schema_file(&registry), supergraph("cached"));
+        assert_eq!(registry.endpoints[0].etag, Some("\"v1\"".to_string()));
+    }
+
+    #[tokio::test]
+    async fn refreshes_the_cache_while_the_supergraph_is_unchanged() {
+        let endpoint = serve(vec![
+            Some(ok_response(&supergraph("a"), "v1")),
+            Some(NOT_MODIFIED),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let cache_dir = dir.path().join("cache");
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.cache = Some(SupergraphCache::new(
+            &cache_dir,
+            Some(Duration::from_secs(60)),
+        ));
+        registry
+            .cache
+            .as_ref()
+            .unwrap()
+            .save(&supergraph("a"), None, None)
+            .await
+            .unwrap();
+
+        // saved long ago
+        let cache_file = cache_dir.join("supergraph.v1.json");
+        let mut file: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&cache_file).unwrap()).unwrap();
+        file["saved_at"] = serde_json::json!(0);
+        std::fs::write(&cache_file, file.to_string()).unwrap();
+        assert!(registry.cache.as_ref().unwrap().load().await.is_err());
+
+        // an unchanged body
+        registry.poll().await;
+        assert!(registry.cache.as_ref().unwrap().load().await.is_ok());
+
+        std::fs::write(&cache_file, file.to_string()).unwrap();
+        std::fs::remove_file(cache_dir.join("confirmed.json")).unwrap();
+
+        // 304
+        registry.poll().await;
+        assert!(registry.cache.as_ref().unwrap().load().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_cdn_at_startup() {
+        let endpoint = serve(vec![
+            Some(SERVER_ERROR),
+            Some(ok_response(&supergraph("b"), "v2")),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.startup_policy = StartupPolicy::Wait;
+        registry.backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(50));
+
+        registry.start().await.unwrap();
+
+        assert_eq!(registry.consecutive_failures, 0);
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_after_the_startup_timeout() {
+        let endpoint = serve(vec![Some(SERVER_ERROR), Some(SERVER_ERROR)]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.startup_policy = StartupPolicy::Wait;
+        registry.startup_timeout = Duration::from_secs(1);
+
+        assert!(matches!(
+            registry.start().await,
+            Err(RegistryError::StartupTimeout { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_schema_file() {
+        let endpoint = serve(vec![Some(SERVER_ERROR)]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let fallback_schema_path = dir
+            .path()
+            .join("fallback.graphql")
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&fallback_schema_path, supergraph("bundled")).unwrap();
+
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.startup_policy = StartupPolicy::Fallback;
+        registry.fallback_schema_path = Some(fallback_schema_path);
+
+        registry.start().await.unwrap();
+
+        assert_eq!(schema_file(&registry), supergraph("bundled"));
+    }
+
+    #[test]
+    fn reads_the_config_before_the_environment() {
+        let env = |name: &str| match name {
+            "HIVE_CDN_POLL_INTERVAL" => Some("20".to_string()),
+            "HIVE_CDN_KEY" => Some("key".to_string()),
+            _ => None,
+        };
+
+        let config: HiveRegistryConfig = serde_json::from_value(
+            serde_json::json!({"poll_interval": 30, "startup_policy": "wait"}),
+        )
+        .unwrap();
+        let config = config.with_env_from(env).unwrap();
+        assert_eq!(config.poll_interval, Some(30));
+        assert_eq!(config.startup_policy, Some(StartupPolicy::Wait));
+        assert_eq!(config.key, Some("key".to_string()));
+
+        let error =
+            serde_json::from_value::<HiveRegistryConfig>(serde_json::json!({"poll_intervl": 30}))
+                .unwrap_err()
+                .to_string();
+        assert!(error.contains("poll_intervl"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_a_key_that_is_not_a_header_value() {
+        let config: HiveRegistryConfig = serde_json::from_value(serde_json::json!({
+            "endpoints": ["http://localhost:4000"],
+            "key": "secret\nkey",
+        }))
+        .unwrap();
+
+        let error = HiveRegistry::configure(config, None)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("HIVE_CDN_KEY"), "{}", error);
+        assert!(!error.contains("secret"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn requires_the_router_to_read_the_supergraph_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir
+            .path()
+            .join("supergraph.graphql")
+            .to_string_lossy()
+            .to_string();
+        std::fs::write(&file_name, supergraph("a")).unwrap();
+        let target = SupergraphTarget::File(file_name.clone());
+        let config: HiveRegistryConfig =
+            serde_json::from_value(serde_json::json!({ "schema_file_path": file_name })).unwrap();
+
+        assert!(reads_the_supergraph_file(&config, &target, &supergraph("a")).await);
+        // the router was started with another supergraph
+        assert!(!reads_the_supergraph_file(&config, &target, &supergraph("b")).await);
+        // nothing tells where the router reads it from
+        assert!(
+            !reads_the_supergraph_file(&HiveRegistryConfig::default(), &target, &supergraph("a"))
+                .await
+        );
+    }
+
+    #[test]
+    fn reports_invalid_environment_variables() {
+        let env = |name: &str| match name {
+            "HIVE_CDN_POLL_INTERVAL" => Some("10s".to_string()),
+            _ => None,
+        };
+
+        let error = HiveRegistryConfig::default()
+            .with_env_from(env)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("HIVE_CDN_POLL_INTERVAL"), "{}", error);
+        assert!(error.contains("\"10s\""), "{}", error);
+
+        assert_eq!(env_var::<u64>(env, "HIVE_CDN_MAX_BACKOFF").unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn streams_the_supergraph_in_memory() {
+        let endpoint = serve(vec![Some(ok_response(&supergraph("b"), "v2"))]).await;
+        let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.target = SupergraphTarget::Stream(sender);
+
+        registry.poll().await;
+
+        assert_eq!(receiver.next().await, Some(supergraph("b")));
+        assert_eq!(registry.supergraph, Some(supergraph("b")));
+    }
+
+    #[tokio::test]
+    async fn holds_a_supergraph_removing_fields_in_use() {
+        let endpoint = serve(vec![
+            Some(ok_response(&supergraph("b"), "v2")),
+            Some(NOT_MODIFIED),
+            Some(NOT_MODIFIED),
+        ])
+        .await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![endpoint], Duration::from_secs(5));
+        registry.usage_guard = UsageGuard::Hold;
+
+        usage_window::enable(Duration::from_secs(3600)).record(
+            &["Query.a".to_string()],
+            OperationRef {
+                operation: "GetA".to_string(),
+                client: Some("web".to_string()),
+            },
+        );
+
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("a"));
+        assert_eq!(registry.endpoints[0].etag, Some("\"v2\"".to_string()));
+
+        // the held supergraph is checked again while the CDN answers with 304
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("a"));
+
+        registry.usage_guard = UsageGuard::Warn;
+        registry.poll().await;
+        assert_eq!(schema_file(&registry), supergraph("b"));
+    }
+
+    #[tokio::test]
+    async fn guards_removed_enum_values_and_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut registry = registry(dir.path(), vec![], Duration::from_secs(5));
+        registry.usage_guard = UsageGuard::Hold;
+
+        usage_window::enable(Duration::from_secs(3600)).record(
+            &[
+                "Query.products".to_string(),
+                "Query.products.category".to_string(),
+                "Category.GAMES".to_string(),
+            ],
+            OperationRef {
+                operation: "GetGames".to_string(),
+                client: None,
+            },
+        );
+
+        let removed = [
+            SupergraphDiff {
+                enum_values_removed: vec!["Category.GAMES".to_string()],
+                ..Default::default()
+            },
+            SupergraphDiff {
+                arguments_removed: vec!["Query.products.category".to_string()],
+                ..Default::default()
+            },
+            // with its arguments
+            SupergraphDiff {
+                fields_removed: vec!["Query.products".to_string()],
+                ..Default::default()
+            },
+        ];
+        for changes in removed {
+            assert!(registry.check_usage(&changes).is_err(), "{}", changes);
+        }
+
+        let unused = SupergraphDiff {
+            enum_values_removed: vec!["Category.BOOKS".to_string()],
+            arguments_removed: vec!["Query.products.first".to_string()],
+            ..Default::default()
+        };
+        assert!(registry.check_usage(&unused).is_ok());
+    }
 }
@@ -0,0 +1,262 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+/// Subgraphs of the supergraph (`/services` artifact), available in the router's `Context`
+pub static SUBGRAPHS_CONTEXT: &str = "hive::subgraphs";
+/// Content of the `/metadata` artifact, available in the router's `Context`
+pub static METADATA_CONTEXT: &str = "hive::metadata";
+
+static CDN_ARTIFACTS: RwLock<Option<Arc<CdnArtifacts>>> = RwLock::new(None);
+
+/// Artifacts served by the CDN next to the supergraph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum CdnArtifact {
+    /// Name, url and SDL of every subgraph
+    Services,
+    /// The public API schema
+    Sdl,
+    /// Metadata attached to the schemas
+    Metadata,
+}
+
+impl CdnArtifact {
+    pub fn path(&self) -> &'static str {
+        match self {
+            CdnArtifact::Services => "services",
+            CdnArtifact::Sdl => "sdl",
+            CdnArtifact::Metadata => "metadata",
+        }
+    }
+}
+
+impl FromStr for CdnArtifact {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "services" => Ok(CdnArtifact::Services),
+            "sdl" => Ok(CdnArtifact::Sdl),
+            "metadata" => Ok(CdnArtifact::Metadata),
+            _ => Err(format!(
+                "unknown artifact \"{}\", expected one of: services, sdl, metadata",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Subgraph {
+    pub name: String,
+    pub url: Option<String>,
+    /// Not added to the `Context`, read it with `cdn_artifacts()`
+    #[serde(skip_serializing)]
+    pub sdl: Option<String>,
+}
+
+/// The latest version of every artifact the registry fetches
+#[derive(Debug, Clone, Default)]
+pub struct CdnArtifacts {
+    pub subgraphs: Option<Vec<Subgraph>>,
+    pub sdl: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    /// Values added to the `Context`, serialized once per change
+    context: Vec<(&'static str, serde_json_bytes::Value)>,
+}
+
+impl CdnArtifacts {
+    pub(crate) fn update(&mut self, artifact: CdnArtifact, content: String) -> Result<(), String> {
+        match artifact {
+            CdnArtifact::Services => {
+                self.subgraphs = Some(serde_json::from_str(&content).map_err(|e| e.to_string())?)
+            }
+            CdnArtifact::Sdl => self.sdl = Some(content),
+            CdnArtifact::Metadata => {
+                self.metadata = Some(serde_json::from_str(&content).map_err(|e| e.to_string())?)
+            }
+        }
+
+        self.context = self.serialize_context()?;
+
+        Ok(())
+    }
+
+    /// Forgets the artifacts that are no longer fetched
+    fn retain(&mut self, artifacts: &[CdnArtifact]) -> Result<(), String> {
+        if !artifacts.contains(&CdnArtifact::Services) {
+            self.subgraphs = None;
+        }
+        if !artifacts.contains(&CdnArtifact::Sdl) {
+            self.sdl = None;
+        }
+        if !artifacts.contains(&CdnArtifact::Metadata) {
+            self.metadata = None;
+        }
+
+        self.context = self.serialize_context()?;
+
+        Ok(())
+    }
+
+    /// Entries the registry adds to the `Context` of every request
+    pub(crate) fn context_entries(&self) -> &[(&'static str, serde_json_bytes::Value)] {
+        &self.context
+    }
+
+    fn serialize_context(&self) -> Result<Vec<(&'static str, serde_json_bytes::Value)>, String> {
+        let mut context = Vec::new();
+        if let Some(subgraphs) = &self.subgraphs {
+            context.push((
+                SUBGRAPHS_CONTEXT,
+                serde_json_bytes::to_value(subgraphs).map_err(|e| e.to_string())?,
+            ));
+        }
+        if let Some(metadata) = &self.metadata {
+            context.push((
+                METADATA_CONTEXT,
+                serde_json_bytes::to_value(metadata).map_err(|e| e.to_string())?,
+            ));
+        }
+
+        Ok(context)
+    }
+}
+
+/// The latest artifacts fetched by the registry.
+/// Returns `None` when the registry is not configured to fetch any of them.
+pub fn cdn_artifacts() -> Option<Arc<CdnArtifacts>> {
+    CDN_ARTIFACTS
+        .read()
+        .ok()
+        .and_then(|artifacts| artifacts.clone())
+}
+
+pub(crate) fn update_cdn_artifact(artifact: CdnArtifact, content: String) -> Result<(), String> {
+    update(&CDN_ARTIFACTS, artifact, content)
+}
+
+/// Keeps only the artifacts the (reloaded) registry fetches, none of them are available without `artifacts`
+pub(crate) fn retain_cdn_artifacts(artifacts: &[CdnArtifact]) {
+    if let Err(e) = retain(&CDN_ARTIFACTS, artifacts) {
+        tracing::warn!(error = %e, "Failed to drop the artifacts that are no longer fetched");
+    }
+}
+
+fn update(
+    slot: &RwLock<Option<Arc<CdnArtifacts>>>,
+    artifact: CdnArtifact,
+    content: String,
+) -> Result<(), String> {
+    let mut artifacts = slot.write().map_err(|e| e.to_string())?;
+
+    // Requests keep reading the previous version until the new one is complete
+    let mut updated = artifacts.as_deref().cloned().unwrap_or_default();
+    updated.update(artifact, content)?;
+    *artifacts = Some(Arc::new(updated));
+
+    Ok(())
+}
+
+fn retain(
+    slot: &RwLock<Option<Arc<CdnArtifacts>>>,
+    artifacts: &[CdnArtifact],
+) -> Result<(), String> {
+    let mut current = slot.write().map_err(|e| e.to_string())?;
+
+    if artifacts.is_empty() {
+        *current = None;
+        return Ok(());
+    }
+
+    if let Some(previous) = current.as_deref() {
+        let mut updated = previous.clone();
+        updated.retain(artifacts)?;
+        *current = Some(Arc::new(updated));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        retain, update, CdnArtifact, CdnArtifacts, Subgraph, METADATA_CONTEXT, SUBGRAPHS_CONTEXT,
+    };
+    use std::sync::RwLock;
+
+    #[test]
+    fn parses_artifacts() {
+        let mut artifacts = CdnArtifacts::default();
+
+        artifacts
+            .update(
+                CdnArtifact::Services,
+                r#"[{"name":"products","url":"http://products","sdl":"type Query { a: String }"}]"#
+                    .to_string(),
+            )
+            .unwrap();
+        artifacts
+            .update(CdnArtifact::Metadata, r#"{"team":"catalog"}"#.to_string())
+            .unwrap();
+        assert!(artifacts
+            .update(CdnArtifact::Metadata, "not json".to_string())
+            .is_err());
+
+        assert_eq!(
+            artifacts.subgraphs,
+            Some(vec![Subgraph {
+                name: "products".to_string(),
+                url: Some("http://products".to_string()),
+                sdl: Some("type Query { a: String }".to_string()),
+            }])
+        );
+        assert_eq!(
+            artifacts.metadata,
+            Some(serde_json::json!({ "team": "catalog" }))
+        );
+        // the SDL stays out of the Context
+        assert_eq!(
+            artifacts.context_entries(),
+            &[
+                (
+                    SUBGRAPHS_CONTEXT,
+                    serde_json_bytes::json!([{ "name": "products", "url": "http://products" }])
+                ),
+                (
+                    METADATA_CONTEXT,
+                    serde_json_bytes::json!({ "team": "catalog" })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn forgets_artifacts_removed_by_a_reload() {
+        let slot = RwLock::new(None);
+        update(
+            &slot,
+            CdnArtifact::Services,
+            r#"[{"name":"products"}]"#.to_string(),
+        )
+        .unwrap();
+        update(
+            &slot,
+            CdnArtifact::Metadata,
+            r#"{"team":"catalog"}"#.to_string(),
+        )
+        .unwrap();
+
+        retain(&slot, &[CdnArtifact::Services]).unwrap();
+        let artifacts = slot.read().unwrap().clone().unwrap();
+        assert!(artifacts.subgraphs.is_some());
+        assert_eq!(artifacts.metadata, None);
+        assert_eq!(artifacts.context_entries().len(), 1);
+        assert_eq!(artifacts.context_entries()[0].0, SUBGRAPHS_CONTEXT);
+
+        retain(&slot, &[]).unwrap();
+        assert!(slot.read().unwrap().is_none());
+    }
+}
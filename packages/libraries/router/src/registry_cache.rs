@@ -0,0 +1,210 @@
+use crate::fs_util::{hash, write_atomically};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The supergraph and its metadata, written together so they always match
+static CACHE_FILE: &str = "supergraph.v1.json";
+/// When the CDN last confirmed the cached supergraph is still the latest one
+static CONFIRMED_FILE: &str = "confirmed.json";
+
+/// Last known good supergraph, persisted so the router can start while the CDN is unreachable.
+#[derive(Debug, Clone)]
+pub struct SupergraphCache {
+    dir: PathBuf,
+    max_age: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CachedSupergraphMetadata {
+    pub etag: Option<String>,
+    pub hash: String,
+    /// Endpoint that served the supergraph, the ETag is only valid for that endpoint
+    pub endpoint: Option<String>,
+    /// When the supergraph was saved, or last confirmed by the CDN (304 or an unchanged body)
+    /// Unit: seconds since UNIX epoch
+    pub saved_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    #[serde(flatten)]
+    metadata: CachedSupergraphMetadata,
+    supergraph: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Confirmation {
+    hash: String,
+    /// Unit: seconds since UNIX epoch
+    confirmed_at: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedSupergraph {
+    pub supergraph: String,
+    pub metadata: CachedSupergraphMetadata,
+}
+
+impl SupergraphCache {
+    pub fn new(dir: impl Into<PathBuf>, max_age: Option<Duration>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_age,
+        }
+    }
+
+    pub async fn save(
+        &self,
+        supergraph: &str,
+        etag: Option<String>,
+        endpoint: Option<String>,
+    ) -> Result<(), String> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", self.dir.display(), e))?;
+
+        let file = CacheFile {
+            metadata: CachedSupergraphMetadata {
+                etag,
+                hash: hash(supergraph.as_bytes()),
+                endpoint,
+                saved_at: now(),
+            },
+            supergraph: supergraph.to_string(),
+        };
+        let file = serde_json::to_string(&file).map_err(|e| e.to_string())?;
+
+        // A single file, a crash never leaves a supergraph without matching metadata
+        write_atomically(&self.path(CACHE_FILE), &file)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Records that the CDN still serves the cached supergraph, so it doesn't age out while it's unchanged.
+    /// Only the small confirmation file is written, not the supergraph.
+    pub async fn confirm(&self, supergraph_hash: &str) -> Result<(), String> {
+        let confirmation = Confirmation {
+            hash: supergraph_hash.to_string(),
+            confirmed_at: now(),
+        };
+        let confirmation = serde_json::to_string(&confirmation).map_err(|e| e.to_string())?;
+
+        write_atomically(&self.path(CONFIRMED_FILE), &confirmation)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Reads the cached supergraph, rejecting it when it's older than `max_age` or doesn't match its hash.
+    pub async fn load(&self) -> Result<CachedSupergraph, String> {
+        let file = tokio::fs::read_to_string(self.path(CACHE_FILE))
+            .await
+            .map_err(|e| format!("failed to read cached supergraph: {}", e))?;
+        let CacheFile {
+            mut metadata,
+            supergraph,
+        } = serde_json::from_str(&file)
+            .map_err(|e| format!("failed to parse cached supergraph: {}", e))?;
+
+        if hash(supergraph.as_bytes()) != metadata.hash {
+            return Err("cached supergraph does not match its hash".to_string());
+        }
+
+        // A confirmation of another supergraph (e.g. written before a crash) is ignored
+        if let Some(confirmation) = tokio::fs::read_to_string(self.path(CONFIRMED_FILE))
+            .await
+            .ok()
+            .and_then(|confirmation| serde_json::from_str::<Confirmation>(&confirmation).ok())
+            .filter(|confirmation| confirmation.hash == metadata.hash)
+        {
+            metadata.saved_at = metadata.saved_at.max(confirmation.confirmed_at);
+        }
+
+        let age = Duration::from_secs(now().saturating_sub(metadata.saved_at));
+        if let Some(max_age) = self.max_age {
+            if age > max_age {
+                return Err(format!(
+                    "cached supergraph is {}s old, the maximum age is {}s",
+                    age.as_secs(),
+                    max_age.as_secs()
+                ));
+            }
+        }
+
+        Ok(CachedSupergraph {
+            supergraph,
+            metadata,
+        })
+    }
+
+    fn path(&self, file_name: &str) -> String {
+        self.dir.join(file_name).to_string_lossy().to_string()
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SupergraphCache, CACHE_FILE};
+    use crate::fs_util::hash;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SupergraphCache::new(dir.path(), Some(Duration::from_secs(60)));
+
+        assert!(cache.load().await.is_err());
+
+        cache
+            .save(
+                "type Query { a: String }",
+                Some("\"v1\"".to_string()),
+                Some("http://cdn/supergraph".to_string()),
+            )
+            .await
+            .unwrap();
+
+        let cached = cache.load().await.unwrap();
+        assert_eq!(cached.supergraph, "type Query { a: String }");
+        assert_eq!(cached.metadata.etag, Some("\"v1\"".to_string()));
+        assert_eq!(
+            cached.metadata.endpoint,
+            Some("http://cdn/supergraph".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_stale_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SupergraphCache::new(dir.path(), Some(Duration::from_secs(60)));
+        cache
+            .save("type Query { a: String }", None, None)
+            .await
+            .unwrap();
+
+        let file = std::fs::read_to_string(dir.path().join(CACHE_FILE)).unwrap();
+        let mut file: serde_json::Value = serde_json::from_str(&file).unwrap();
+        file["saved_at"] = serde_json::json!(0);
+        std::fs::write(dir.path().join(CACHE_FILE), file.to_string()).unwrap();
+
+        assert!(cache.load().await.is_err());
+        assert!(SupergraphCache::new(dir.path(), None).load().await.is_ok());
+
+        // confirming another supergraph doesn't refresh it
+        cache.confirm("another").await.unwrap();
+        assert!(cache.load().await.is_err());
+
+        cache
+            .confirm(&hash("type Query { a: String }".as_bytes()))
+            .await
+            .unwrap();
+        assert!(cache.load().await.is_ok());
+    }
+}
@@ -0,0 +1,379 @@
+use graphql_parser::schema::{
+    parse_schema, Definition, Directive, Document, Field, TypeDefinition, Value,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// What changed between two supergraphs
+#[derive(Debug, Default, PartialEq)]
+pub struct SupergraphDiff {
+    pub types_added: Vec<String>,
+    pub types_removed: Vec<String>,
+    pub fields_added: Vec<String>,
+    pub fields_removed: Vec<String>,
+    /// `Enum.VALUE` coordinates
+    pub enum_values_added: Vec<String>,
+    pub enum_values_removed: Vec<String>,
+    /// `Type.field.argument` coordinates
+    pub arguments_added: Vec<String>,
+    pub arguments_removed: Vec<String>,
+    pub subgraphs_added: Vec<String>,
+    pub subgraphs_removed: Vec<String>,
+    pub ownership_changed: Vec<OwnershipChange>,
+}
+
+/// A field resolved by a different set of subgraphs
+#[derive(Debug, PartialEq)]
+pub struct OwnershipChange {
+    pub coordinate: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl SupergraphDiff {
+    pub fn is_empty(&self) -> bool {
+        self.types_added.is_empty()
+            && self.types_removed.is_empty()
+            && self.fields_added.is_empty()
+            && self.fields_removed.is_empty()
+            && self.enum_values_added.is_empty()
+            && self.enum_values_removed.is_empty()
+            && self.arguments_added.is_empty()
+            && self.arguments_removed.is_empty()
+            && self.subgraphs_added.is_empty()
+            && self.subgraphs_removed.is_empty()
+            && self.ownership_changed.is_empty()
+    }
+}
+
+impl fmt::Display for SupergraphDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(
+                f,
+                "no changes to types, fields, enum values, arguments or subgraphs"
+            );
+        }
+
+        let mut parts = Vec::new();
+        let mut list = |label: &str, items: &Vec<String>| {
+            if !items.is_empty() {
+                parts.push(format!("{} {} ({})", items.len(), label, items.join(", ")));
+            }
+        };
+
+        list("subgraphs added", &self.subgraphs_added);
+        list("subgraphs removed", &self.subgraphs_removed);
+        list("types added", &self.types_added);
+        list("types removed", &self.types_removed);
+        list("fields added", &self.fields_added);
+        list("fields removed", &self.fields_removed);
+        list("enum values added", &self.enum_values_added);
+        list("enum values removed", &self.enum_values_removed);
+        list("arguments added", &self.arguments_added);
+        list("arguments removed", &self.arguments_removed);
+
+        if !self.ownership_changed.is_empty() {
+            parts.push(format!(
+                "{} fields changed ownership ({})",
+                self.ownership_changed.len(),
+                self.ownership_changed
+                    .iter()
+                    .map(|change| format!(
+                        "{}: [{}] -> [{}]",
+                        change.coordinate,
+                        change.before.join(", "),
+                        change.after.join(", ")
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+/// The parts of a supergraph that are compared
+#[derive(Default)]
+struct Summary {
+    types: BTreeSet<String>,
+    /// Field coordinates and the subgraphs resolving them
+    fields: BTreeMap<String, BTreeSet<String>>,
+    enum_values: BTreeSet<String>,
+    arguments: BTreeSet<String>,
+    subgraphs: BTreeSet<String>,
+}
+
+pub fn diff(old: &str, new: &str) -> Result<SupergraphDiff, String> {
+    let old = parse_schema::<String>(old)
+        .map_err(|e| e.to_string())?
+        .into_static();
+    let new = parse_schema::<String>(new)
+        .map_err(|e| e.to_string())?
+        .into_static();
+    let old = summarize(&old);
+    let new = summarize(&new);
+
+    let ownership_changed = old
+        .fields
+        .iter()
+        .filter_map(|(coordinate, before)| {
+            let after = new.fields.get(coordinate)?;
+
+            match before != after {
+                true => Some(OwnershipChange {
+                    coordinate: coordinate.clone(),
+                    before: before.iter().cloned().collect(),
+                    after: after.iter().cloned().collect(),
+                }),
+                false => None,
+            }
+        })
+        .collect();
+
+    let old_fields: BTreeSet<String> = old.fields.keys().cloned().collect();
+    let new_fields: BTreeSet<String> = new.fields.keys().cloned().collect();
+
+    Ok(SupergraphDiff {
+        types_added: new.types.difference(&old.types).cloned().collect(),
+        types_removed: old.types.difference(&new.types).cloned().collect(),
+        fields_added: new_fields.difference(&old_fields).cloned().collect(),
+        fields_removed: old_fields.difference(&new_fields).cloned().collect(),
+        enum_values_added: new
+            .enum_values
+            .difference(&old.enum_values)
+            .cloned()
+            .collect(),
+        enum_values_removed: old
+            .enum_values
+            .difference(&new.enum_values)
+            .cloned()
+            .collect(),
+        arguments_added: new.arguments.difference(&old.arguments).cloned().collect(),
+        arguments_removed: old.arguments.difference(&new.arguments).cloned().collect(),
+        subgraphs_added: new.subgraphs.difference(&old.subgraphs).cloned().collect(),
+        subgraphs_removed: old.subgraphs.difference(&new.subgraphs).cloned().collect(),
+        ownership_changed,
+    })
+}
+
+fn summarize(document: &Document<'static, String>) -> Summary {
+    let mut summary = Summary::default();
+    // join__Graph enum values, mapped to subgraph names
+    let mut graphs: BTreeMap<String, String> = BTreeMap::new();
+
+    for definition in &document.definitions {
+        if let Definition::TypeDefinition(TypeDefinition::Enum(enum_type)) = definition {
+            if enum_type.name == "join__Graph" {
+                for value in &enum_type.values {
+                    let name = find_directive(&value.directives, "join__graph")
+                        .and_then(|directive| string_argument(directive, "name"))
+                        .unwrap_or_else(|| value.name.clone());
+                    graphs.insert(value.name.clone(), name);
+                }
+            }
+        }
+    }
+
+    summary.subgraphs = graphs.values().cloned().collect();
+
+    let owners = |directives: &Vec<Directive<'static, String>>, name: &str| {
+        directives
+            .iter()
+            .filter(|directive| directive.name == name)
+            .filter_map(|directive| enum_argument(directive, "graph"))
+            .map(|graph| graphs.get(&graph).cloned().unwrap_or(graph))
+            .collect::<BTreeSet<String>>()
+    };
+
+    for definition in &document.definitions {
+        let Definition::TypeDefinition(type_definition) = definition else {
+            continue;
+        };
+
+        let (name, type_owners, fields) = match type_definition {
+            TypeDefinition::Object(t) => (
+                &t.name,
+                owners(&t.directives, "join__type"),
+                t.fields
+                    .iter()
+                    .map(|field| (&field.name, owners(&field.directives, "join__field")))
+                    .collect::<Vec<_>>(),
+            ),
+            TypeDefinition::Interface(t) => (
+                &t.name,
+                owners(&t.directives, "join__type"),
+                t.fields
+                    .iter()
+                    .map(|field| (&field.name, owners(&field.directives, "join__field")))
+                    .collect(),
+            ),
+            TypeDefinition::InputObject(t) => (
+                &t.name,
+                owners(&t.directives, "join__type"),
+                t.fields
+                    .iter()
+                    .map(|field| (&field.name, owners(&field.directives, "join__field")))
+                    .collect(),
+            ),
+            TypeDefinition::Scalar(t) => (&t.name, BTreeSet::new(), vec![]),
+            TypeDefinition::Union(t) => (&t.name, BTreeSet::new(), vec![]),
+            TypeDefinition::Enum(t) => (&t.name, BTreeSet::new(), vec![]),
+        };
+
+        // Federation's own definitions are not interesting to anyone
+        if name.starts_with("join__") || name.starts_with("link__") {
+            continue;
+        }
+
+        summary.types.insert(name.clone());
+
+        match type_definition {
+            TypeDefinition::Object(t) => summary.arguments.extend(arguments(name, &t.fields)),
+            TypeDefinition::Interface(t) => summary.arguments.extend(arguments(name, &t.fields)),
+            TypeDefinition::Enum(t) => summary.enum_values.extend(
+                t.values
+                    .iter()
+                    .map(|value| format!("{}.{}", name, value.name)),
+            ),
+            _ => {}
+        }
+
+        for (field_name, field_owners) in fields {
+            // A field without @join__field is resolved by every subgraph defining the type
+            let field_owners = match field_owners.is_empty() {
+                true => type_owners.clone(),
+                false => field_owners,
+            };
+            summary
+                .fields
+                .insert(format!("{}.{}", name, field_name), field_owners);
+        }
+    }
+
+    summary
+}
+
+/// `Type.field.argument` coordinates of the arguments of `fields`
+fn arguments<'a>(
+    type_name: &'a str,
+    fields: &'a [Field<'static, String>],
+) -> impl Iterator<Item = String> + 'a {
+    fields.iter().flat_map(move |field| {
+        field
+            .arguments
+            .iter()
+            .map(move |argument| format!("{}.{}.{}", type_name, field.name, argument.name))
+    })
+}
+
+fn find_directive<'a>(
+    directives: &'a [Directive<'static, String>],
+    name: &str,
+) -> Option<&'a Directive<'static, String>> {
+    directives.iter().find(|directive| directive.name == name)
+}
+
+fn string_argument(directive: &Directive<'static, String>, name: &str) -> Option<String> {
+    directive
+        .arguments
+        .iter()
+        .find_map(|(argument, value)| match (argument == name, value) {
+            (true, Value::String(value)) => Some(value.clone()),
+            _ => None,
+        })
+}
+
+fn enum_argument(directive: &Directive<'static, String>, name: &str) -> Option<String> {
+    directive
+        .arguments
+        .iter()
+        .find_map(|(argument, value)| match (argument == name, value) {
+            (true, Value::Enum(value)) => Some(value.clone()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, OwnershipChange};
+
+    const SUPERGRAPH: &str = r#"
+        directive @join__graph(name: String!, url: String!) on ENUM_VALUE
+        directive @join__type(graph: join__Graph!) repeatable on OBJECT
+        directive @join__field(graph: join__Graph) repeatable on FIELD_DEFINITION
+
+        enum join__Graph {
+            INVENTORY @join__graph(name: "inventory", url: "http://inventory")
+            PRODUCTS @join__graph(name: "products", url: "http://products")
+        }
+
+        type Query @join__type(graph: PRODUCTS) {
+            products(first: Int, category: Category): [Product]
+        }
+
+        enum Category @join__type(graph: PRODUCTS) {
+            BOOKS
+            GAMES
+        }
+
+        type Product @join__type(graph: INVENTORY) @join__type(graph: PRODUCTS) {
+            id: ID!
+            name: String @join__field(graph: PRODUCTS)
+            price: Int @join__field(graph: INVENTORY)
+        }
+    "#;
+
+    #[test]
+    fn no_changes() {
+        let changes = diff(SUPERGRAPH, SUPERGRAPH).unwrap();
+
+        assert!(changes.is_empty());
+    }
+
+    #[test]
+    fn detects_changes() {
+        let new = SUPERGRAPH
+            .replace(
+                r#"PRODUCTS @join__graph(name: "products", url: "http://products")"#,
+                r#"PRODUCTS @join__graph(name: "products", url: "http://products")
+                REVIEWS @join__graph(name: "reviews", url: "http://reviews")"#,
+            )
+            .replace(
+                "name: String @join__field(graph: PRODUCTS)",
+                "reviews: [Review] @join__field(graph: REVIEWS)",
+            )
+            .replace(
+                "price: Int @join__field(graph: INVENTORY)",
+                "price: Int @join__field(graph: PRODUCTS)",
+            )
+            .replace(
+                "products(first: Int, category: Category)",
+                "products(category: Category)",
+            )
+            .replace("GAMES", "MUSIC")
+            + "type Review @join__type(graph: REVIEWS) { id: ID! }";
+
+        let changes = diff(SUPERGRAPH, &new).unwrap();
+
+        assert_eq!(changes.subgraphs_added, vec!["reviews"]);
+        assert!(changes.subgraphs_removed.is_empty());
+        assert_eq!(changes.types_added, vec!["Review"]);
+        assert!(changes.types_removed.is_empty());
+        assert_eq!(changes.fields_added, vec!["Product.reviews", "Review.id"]);
+        assert_eq!(changes.fields_removed, vec!["Product.name"]);
+        assert_eq!(changes.enum_values_added, vec!["Category.MUSIC"]);
+        assert_eq!(changes.enum_values_removed, vec!["Category.GAMES"]);
+        assert!(changes.arguments_added.is_empty());
+        assert_eq!(changes.arguments_removed, vec!["Query.products.first"]);
+        assert_eq!(
+            changes.ownership_changed,
+            vec![OwnershipChange {
+                coordinate: "Product.price".to_string(),
+                before: vec!["inventory".to_string()],
+                after: vec!["products".to_string()],
+            }]
+        );
+    }
+}
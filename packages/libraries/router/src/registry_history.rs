@@ -0,0 +1,173 @@
+use crate::fs_util::{hash, write_atomically};
+use crate::registry_cache::now;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+static INDEX_FILE: &str = "history.json";
+
+/// The last supergraphs applied by the registry, keyed by hash.
+/// Lets an operator roll back to one of them with `pin`, without reaching the CDN.
+#[derive(Debug, Clone)]
+pub struct SupergraphHistory {
+    dir: PathBuf,
+    size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HistoryEntry {
+    pub hash: String,
+    pub etag: Option<String>,
+    /// Unit: seconds since UNIX epoch
+    pub saved_at: u64,
+}
+
+impl HistoryEntry {
+    fn matches(&self, pin: &str) -> bool {
+        pin_matches(pin, &self.hash, self.etag.as_deref())
+    }
+}
+
+/// A pin is either the SHA-256 of a supergraph or the version (ETag) the CDN served it with
+pub(crate) fn pin_matches(pin: &str, supergraph_hash: &str, etag: Option<&str>) -> bool {
+    let pin = pin.trim().trim_matches('"');
+
+    pin.eq_ignore_ascii_case(supergraph_hash)
+        || etag.is_some_and(|etag| etag.trim_matches('"') == pin)
+}
+
+impl SupergraphHistory {
+    pub fn new(dir: impl Into<PathBuf>, size: usize) -> Self {
+        Self {
+            dir: dir.into(),
+            size: size.max(1),
+        }
+    }
+
+    /// Adds the supergraph to the history, newest first, and forgets the ones beyond `size`.
+    pub async fn save(&self, supergraph: &str, etag: Option<String>) -> Result<(), String> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", self.dir.display(), e))?;
+
+        let supergraph_hash = hash(supergraph.as_bytes());
+        let mut entries = self.entries().await.unwrap_or_default();
+        entries.retain(|entry| entry.hash != supergraph_hash);
+
+        write_atomically(&self.path(&supergraph_hash), supergraph)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        entries.insert(
+            0,
+            HistoryEntry {
+                hash: supergraph_hash,
+                etag,
+                saved_at: now(),
+            },
+        );
+        let forgotten = entries.split_off(entries.len().min(self.size));
+
+        let index = serde_json::to_string_pretty(&entries).map_err(|e| e.to_string())?;
+        write_atomically(&self.dir.join(INDEX_FILE).to_string_lossy(), &index)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        for entry in forgotten {
+            let _ = tokio::fs::remove_file(self.path(&entry.hash)).await;
+        }
+
+        Ok(())
+    }
+
+    /// Supergraphs in the history, newest first
+    pub async fn entries(&self) -> Result<Vec<HistoryEntry>, String> {
+        let index = tokio::fs::read_to_string(self.dir.join(INDEX_FILE))
+            .await
+            .map_err(|e| format!("failed to read history index: {}", e))?;
+
+        serde_json::from_str(&index).map_err(|e| format!("failed to parse history index: {}", e))
+    }
+
+    /// Finds the supergraph matching the pin, `None` when it's not in the history.
+    pub async fn find(&self, pin: &str) -> Result<Option<(HistoryEntry, String)>, String> {
+        let Some(entry) = self
+            .entries()
+            .await?
+            .into_iter()
+            .find(|entry| entry.matches(pin))
+        else {
+            return Ok(None);
+        };
+
+        let supergraph = tokio::fs::read_to_string(self.path(&entry.hash))
+            .await
+            .map_err(|e| format!("failed to read supergraph {}: {}", entry.hash, e))?;
+
+        if hash(supergraph.as_bytes()) != entry.hash {
+            return Err(format!("supergraph {} does not match its hash", entry.hash));
+        }
+
+        Ok(Some((entry, supergraph)))
+    }
+
+    fn path(&self, supergraph_hash: &str) -> String {
+        self.dir
+            .join(format!("{}.graphql", supergraph_hash))
+            .to_string_lossy()
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pin_matches, SupergraphHistory};
+    use crate::fs_util::hash;
+
+    #[test]
+    fn matches_hash_or_etag() {
+        assert!(pin_matches("abc123", "ABC123", None));
+        assert!(pin_matches("v1", "ABC123", Some("\"v1\"")));
+        assert!(pin_matches("\"v1\"", "ABC123", Some("\"v1\"")));
+        assert!(!pin_matches("v2", "ABC123", Some("\"v1\"")));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_last_supergraphs() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = SupergraphHistory::new(dir.path(), 2);
+
+        history
+            .save("type Query { a: String }", Some("\"v1\"".to_string()))
+            .await
+            .unwrap();
+        history
+            .save("type Query { b: String }", Some("\"v2\"".to_string()))
+            .await
+            .unwrap();
+        history
+            .save("type Query { c: String }", Some("\"v3\"".to_string()))
+            .await
+            .unwrap();
+
+        let entries = history.entries().await.unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.etag.clone()).collect::<Vec<_>>(),
+            vec![Some("\"v3\"".to_string()), Some("\"v2\"".to_string())]
+        );
+        // index and two supergraphs
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+
+        assert!(history.find("v1").await.unwrap().is_none());
+
+        let (entry, supergraph) = history.find("v2").await.unwrap().unwrap();
+        assert_eq!(supergraph, "type Query { b: String }");
+
+        let (_, supergraph) = history
+            .find(&entry.hash.to_lowercase())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(supergraph, "type Query { b: String }");
+        assert_eq!(entry.hash, hash(supergraph.as_bytes()));
+    }
+}
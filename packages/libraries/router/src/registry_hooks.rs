@@ -0,0 +1,288 @@
+use serde::Serialize;
+use std::process::Stdio;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+/// A supergraph applied by the registry, sent to the on-change hooks
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SupergraphChange {
+    pub previous_hash: Option<String>,
+    pub hash: String,
+    pub etag: Option<String>,
+    /// `None` when the supergraph is streamed to the router
+    pub file_path: Option<String>,
+}
+
+/// Notifies sidecars about a new supergraph, by spawning an executable and/or POSTing to a URL.
+#[derive(Debug, Clone)]
+pub struct ChangeHooks {
+    runner: Arc<HookRunner>,
+    /// Changes waiting for the hooks, the worker starts with the first one
+    queue: Arc<OnceLock<mpsc::UnboundedSender<SupergraphChange>>>,
+}
+
+#[derive(Debug)]
+struct HookRunner {
+    command: Option<String>,
+    url: Option<String>,
+    client: reqwest::Client,
+    timeout: Duration,
+}
+
+impl ChangeHooks {
+    /// Returns `None` when no hook is configured.
+    pub fn new(
+        command: Option<String>,
+        url: Option<String>,
+        timeout: Duration,
+    ) -> Result<Option<Self>, String> {
+        if command.is_none() && url.is_none() {
+            return Ok(None);
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(Some(Self {
+            runner: Arc::new(HookRunner {
+                command,
+                url,
+                client,
+                timeout,
+            }),
+            queue: Arc::new(OnceLock::new()),
+        }))
+    }
+
+    /// Runs the hooks in the background, one change at a time and in order,
+    /// so a sidecar never sees an older supergraph after a newer one.
+    /// Failures are logged, they never hold back the supergraph update.
+    pub fn notify(&self, change: SupergraphChange) {
+        let queue = self.queue.get_or_init(|| {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<SupergraphChange>();
+            let runner = self.runner.clone();
+
+            // Stops once every copy of the hooks is dropped and the queue is drained
+            tokio::task::spawn(async move {
+                while let Some(change) = receiver.recv().await {
+                    if let Err(e) = runner.run(&change).await {
+                        tracing::error!(hash = %change.hash, error = %e, "Supergraph on-change hook failed");
+                    }
+                }
+            });
+
+            sender
+        });
+
+        let _ = queue.send(change);
+    }
+}
+
+impl HookRunner {
+    async fn run(&self, change: &SupergraphChange) -> Result<(), String> {
+        let payload = serde_json::to_string(change).map_err(|e| e.to_string())?;
+        let mut errors = Vec::new();
+
+        if let Some(command) = &self.command {
+            if let Err(e) = self.run_command(command, change, &payload).await {
+                errors.push(format!("{}: {}", command, e));
+            }
+        }
+
+        if let Some(url) = &self.url {
+            if let Err(e) = self.post(url, &payload).await {
+                errors.push(format!("{}: {}", url, e));
+            }
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ")),
+        }
+    }
+
+    /// The payload is written to stdin, and is also available as `HIVE_SUPERGRAPH_*` environment variables.
+    async fn run_command(
+        &self,
+        command: &str,
+        change: &SupergraphChange,
+        payload: &str,
+    ) -> Result<(), String> {
+        let mut child = tokio::process::Command::new(command)
+            .env("HIVE_SUPERGRAPH_HASH", &change.hash)
+            .env(
+                "HIVE_SUPERGRAPH_PREVIOUS_HASH",
+                change.previous_hash.as_deref().unwrap_or_default(),
+            )
+            .env(
+                "HIVE_SUPERGRAPH_ETAG",
+                change.etag.as_deref().unwrap_or_default(),
+            )
+            .env(
+                "HIVE_SUPERGRAPH_PATH",
+                change.file_path.as_deref().unwrap_or_default(),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("failed to spawn: {}", e))?;
+
+        let stdin = child.stdin.take();
+        let completion = async {
+            if let Some(mut stdin) = stdin {
+                // The command is free to ignore its input, dropping stdin closes it
+                let _ = stdin.write_all(payload.as_bytes()).await;
+            }
+
+            child.wait().await
+        };
+
+        // A command that never reads a large payload blocks the write, the timeout covers both
+        let status = tokio::time::timeout(self.timeout, completion)
+            .await
+            .map_err(|_| format!("timed out after {:?}", self.timeout))?
+            .map_err(|e| e.to_string())?;
+
+        match status.success() {
+            true => Ok(()),
+            false => Err(format!("exited with {}", status)),
+        }
+    }
+
+    async fn post(&self, url: &str, payload: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload.to_string())
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        match response.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("unexpected response ({})", response.status())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChangeHooks, SupergraphChange};
+    use crate::test_support::StandIn;
+    use std::time::Duration;
+
+    fn change() -> SupergraphChange {
+        SupergraphChange {
+            previous_hash: Some("A".to_string()),
+            hash: "B".to_string(),
+            etag: Some("\"v2\"".to_string()),
+            file_path: Some("/tmp/supergraph.graphql".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn posts_the_change() {
+        let webhook = StandIn::serve(vec![Some("HTTP/1.1 204 No Content\r\n\r\n")]).await;
+
+        let hooks = ChangeHooks::new(
+            None,
+            Some(webhook.url("/hooks/supergraph")),
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .unwrap();
+        hooks.runner.run(&change()).await.unwrap();
+
+        let request = &webhook.requests()[0];
+        assert!(request.starts_with("POST /hooks/supergraph"));
+        assert!(request.ends_with(&serde_json::to_string(&change()).unwrap()));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_the_command() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let script = dir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$HIVE_SUPERGRAPH_PREVIOUS_HASH -> $HIVE_SUPERGRAPH_HASH\" > {}\ncat >> {}\n",
+                output.display(),
+                output.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let hooks = ChangeHooks::new(
+            Some(script.to_string_lossy().to_string()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .unwrap();
+        hooks.runner.run(&change()).await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            format!("A -> B\n{}", serde_json::to_string(&change()).unwrap())
+        );
+
+        let failing =
+            ChangeHooks::new(Some("/bin/false".to_string()), None, Duration::from_secs(5))
+                .unwrap()
+                .unwrap();
+        assert!(failing.runner.run(&change()).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn runs_the_hooks_one_change_at_a_time() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output");
+        let script = dir.path().join("hook.sh");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"start $HIVE_SUPERGRAPH_HASH\" >> {0}\nsleep 0.2\necho \"end $HIVE_SUPERGRAPH_HASH\" >> {0}\n",
+                output.display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let hooks = ChangeHooks::new(
+            Some(script.to_string_lossy().to_string()),
+            None,
+            Duration::from_secs(5),
+        )
+        .unwrap()
+        .unwrap();
+        hooks.notify(change());
+        hooks.notify(SupergraphChange {
+            previous_hash: Some("B".to_string()),
+            hash: "C".to_string(),
+            ..change()
+        });
+
+        let expected = "start B\nend B\nstart C\nend C\n";
+        for _ in 0..50 {
+            if std::fs::read_to_string(&output).unwrap_or_default() == expected {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), expected);
+    }
+}
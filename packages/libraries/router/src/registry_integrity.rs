@@ -0,0 +1,216 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use reqwest::header::HeaderMap;
+use sha2::{Digest, Sha256};
+
+pub static DEFAULT_SIGNATURE_HEADER: &str = "X-Hive-Signature";
+
+/// DER prefix of an Ed25519 `SubjectPublicKeyInfo` (RFC 8410), followed by the 32 bytes of the key
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Checks the supergraph was not tampered with before it's applied.
+/// A checksum header compares the SHA-256 of the body,
+/// a detached Ed25519 signature is verified against the configured public key.
+#[derive(Debug, Clone)]
+pub struct IntegrityCheck {
+    checksum_header: Option<String>,
+    signature: Option<(String, VerifyingKey)>,
+}
+
+impl IntegrityCheck {
+    /// Returns `None` when neither a checksum header nor a public key is configured.
+    pub fn new(
+        checksum_header: Option<String>,
+        signature_header: Option<String>,
+        public_key: Option<String>,
+    ) -> Result<Option<Self>, String> {
+        let signature = match public_key {
+            Some(public_key) => Some((
+                signature_header.unwrap_or_else(|| DEFAULT_SIGNATURE_HEADER.to_string()),
+                parse_public_key(&public_key)?,
+            )),
+            None => None,
+        };
+
+        if checksum_header.is_none() && signature.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            checksum_header,
+            signature,
+        }))
+    }
+
+    pub fn verify(&self, body: &str, headers: &HeaderMap) -> Result<(), String> {
+        if let Some(checksum_header) = &self.checksum_header {
+            let expected = header(headers, checksum_header)?;
+            let digest = Sha256::digest(body.as_bytes());
+
+            if !checksum_matches(expected, &digest) {
+                return Err(format!(
+                    "checksum mismatch, {} is {} but the body hashes to {:x}",
+                    checksum_header, expected, digest
+                ));
+            }
+        }
+
+        if let Some((signature_header, public_key)) = &self.signature {
+            let signature = BASE64
+                .decode(header(headers, signature_header)?)
+                .map_err(|e| format!("invalid {}: {}", signature_header, e))
+                .and_then(|bytes| {
+                    Signature::from_slice(&bytes)
+                        .map_err(|e| format!("invalid {}: {}", signature_header, e))
+                })?;
+
+            public_key
+                .verify(body.as_bytes(), &signature)
+                .map_err(|_| "signature does not match the public key".to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Result<&'a str, String> {
+    headers
+        .get(name)
+        .ok_or_else(|| format!("{} header is missing", name))?
+        .to_str()
+        .map(|value| value.trim())
+        .map_err(|e| format!("invalid {}: {}", name, e))
+}
+
+/// The checksum is the hex or base64 encoded SHA-256, optionally prefixed with `sha256=` or `sha-256=`
+fn checksum_matches(expected: &str, digest: &[u8]) -> bool {
+    let expected = expected
+        .split_once('=')
+        .filter(|(algorithm, _)| {
+            algorithm.eq_ignore_ascii_case("sha256") || algorithm.eq_ignore_ascii_case("sha-256")
+        })
+        .map(|(_, value)| value)
+        .unwrap_or(expected);
+
+    let hex = digest
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    expected.eq_ignore_ascii_case(&hex)
+        || BASE64.decode(expected).is_ok_and(|bytes| bytes == digest)
+}
+
+/// Reads a base64 encoded Ed25519 public key, raw (32 bytes) or in its DER / PEM form (`MCowBQYDK2VwAyEA...`).
+/// Other key types and truncated keys are rejected.
+fn parse_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+    let pem_body = public_key
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect::<String>();
+    let bytes = BASE64
+        .decode(pem_body.trim())
+        .map_err(|e| format!("invalid public key: {}", e))?;
+
+    let raw = match bytes.len() {
+        32 => bytes.as_slice(),
+        44 if bytes.starts_with(&ED25519_SPKI_PREFIX) => &bytes[ED25519_SPKI_PREFIX.len()..],
+        _ => return Err("invalid public key: expected an Ed25519 key".to_string()),
+    };
+    let raw: [u8; 32] = raw
+        .try_into()
+        .map_err(|_| "invalid public key: expected an Ed25519 key".to_string())?;
+
+    VerifyingKey::from_bytes(&raw).map_err(|e| format!("invalid public key: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_public_key, IntegrityCheck, BASE64, DEFAULT_SIGNATURE_HEADER, ED25519_SPKI_PREFIX,
+    };
+    use base64::Engine;
+    use ed25519_dalek::{Signer, SigningKey};
+    use reqwest::header::{HeaderMap, HeaderValue};
+    use sha2::{Digest, Sha256};
+
+    const BODY: &str = "type Query { a: String }";
+
+    #[test]
+    fn verifies_checksum() {
+        let check = IntegrityCheck::new(Some("X-Checksum".to_string()), None, None)
+            .unwrap()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        assert!(check.verify(BODY, &headers).is_err());
+
+        let hex = format!("{:x}", Sha256::digest(BODY.as_bytes()));
+        headers.insert("X-Checksum", HeaderValue::from_str(&hex).unwrap());
+        assert!(check.verify(BODY, &headers).is_ok());
+        assert!(check.verify("type Query { b: String }", &headers).is_err());
+
+        let base64 = format!("sha-256={}", BASE64.encode(Sha256::digest(BODY.as_bytes())));
+        headers.insert("X-Checksum", HeaderValue::from_str(&base64).unwrap());
+        assert!(check.verify(BODY, &headers).is_ok());
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = BASE64.encode(signing_key.verifying_key().as_bytes());
+        let check = IntegrityCheck::new(None, None, Some(public_key))
+            .unwrap()
+            .unwrap();
+
+        let mut headers = HeaderMap::new();
+        assert!(check.verify(BODY, &headers).is_err());
+
+        let signature = BASE64.encode(signing_key.sign(BODY.as_bytes()).to_bytes());
+        headers.insert(
+            DEFAULT_SIGNATURE_HEADER,
+            HeaderValue::from_str(&signature).unwrap(),
+        );
+        assert!(check.verify(BODY, &headers).is_ok());
+        assert!(check.verify("type Query { b: String }", &headers).is_err());
+    }
+
+    #[test]
+    fn parses_ed25519_keys_only() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let raw = signing_key.verifying_key().to_bytes();
+        let der = [ED25519_SPKI_PREFIX.as_slice(), raw.as_slice()].concat();
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            BASE64.encode(&der)
+        );
+
+        assert_eq!(
+            parse_public_key(&BASE64.encode(raw)).unwrap().to_bytes(),
+            raw
+        );
+        assert_eq!(
+            parse_public_key(&BASE64.encode(&der)).unwrap().to_bytes(),
+            raw
+        );
+        assert_eq!(parse_public_key(&pem).unwrap().to_bytes(), raw);
+
+        // truncated
+        assert!(parse_public_key(&BASE64.encode(&der[..40])).is_err());
+        // a P-256 key, which also ends with 32 bytes
+        let mut p256 = vec![
+            0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06,
+            0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04,
+        ];
+        p256.extend_from_slice(&[1; 64]);
+        assert!(parse_public_key(&BASE64.encode(p256)).is_err());
+    }
+
+    #[test]
+    fn disabled_without_configuration() {
+        assert!(IntegrityCheck::new(None, None, None).unwrap().is_none());
+        assert!(IntegrityCheck::new(None, None, Some("not a key".to_string())).is_err());
+    }
+}
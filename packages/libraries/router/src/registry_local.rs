@@ -0,0 +1,179 @@
+use crate::fs_util::hash;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+static EXTENSIONS: [&str; 3] = ["graphql", "graphqls", "gql"];
+
+/// Reads the supergraph from disk instead of GraphQL Hive CDN, for local development.
+/// The path is either a supergraph file or a directory of versioned supergraph files,
+/// in which case the latest version (by file name) is used.
+#[derive(Debug, Clone)]
+pub struct LocalSource {
+    path: PathBuf,
+    /// Hash of the last supergraph read, so an unchanged file is reported as not modified
+    last_read: Option<String>,
+}
+
+/// A supergraph read from disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalSupergraph {
+    /// Name of the file, the version of the supergraph
+    pub version: String,
+    /// `None` when the file did not change since the last read
+    pub supergraph: Option<String>,
+}
+
+impl LocalSource {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            last_read: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn read(&mut self) -> Result<LocalSupergraph, String> {
+        let file = match tokio::fs::metadata(&self.path).await {
+            Ok(metadata) if metadata.is_dir() => latest_version(&self.path).await?,
+            Ok(_) => self.path.clone(),
+            Err(e) => return Err(format!("failed to read {}: {}", self.path.display(), e)),
+        };
+
+        let supergraph = tokio::fs::read_to_string(&file)
+            .await
+            .map_err(|e| format!("failed to read {}: {}", file.display(), e))?;
+        let supergraph_hash = hash(supergraph.as_bytes());
+        let changed = self.last_read.as_ref() != Some(&supergraph_hash);
+        self.last_read = Some(supergraph_hash);
+
+        Ok(LocalSupergraph {
+            version: file
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            supergraph: changed.then_some(supergraph),
+        })
+    }
+}
+
+/// The supergraph file with the highest version in the directory.
+/// Hidden files are skipped, they are usually written by editors or `write_atomically`.
+async fn latest_version(dir: &Path) -> Result<PathBuf, String> {
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| format!("failed to read {}: {}", dir.display(), e))?;
+    let mut latest: Option<(String, PathBuf)> = None;
+
+    while let Some(entry) = entries.next_entry().await.map_err(|e| e.to_string())? {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_supergraph = path
+            .extension()
+            .is_some_and(|extension| EXTENSIONS.iter().any(|e| extension == *e));
+
+        if name.starts_with('.') || !is_supergraph || !path.is_file() {
+            continue;
+        }
+
+        match &latest {
+            Some((latest_name, _)) if compare_versions(&name, latest_name) != Ordering::Greater => {
+            }
+            _ => latest = Some((name, path)),
+        }
+    }
+
+    latest
+        .map(|(_, path)| path)
+        .ok_or_else(|| format!("no supergraph files found in {}", dir.display()))
+}
+
+/// Compares file names with their numbers by value, so `v10` comes after `v9`
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a, b) = (chunks(a), chunks(b));
+
+    for (a, b) in a.iter().zip(b.iter()) {
+        let ordering = match (a.parse::<u128>(), b.parse::<u128>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+/// Splits a file name into runs of digits and runs of everything else
+fn chunks(name: &str) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut previous_is_digit = None;
+
+    for (index, c) in name.char_indices() {
+        let is_digit = c.is_ascii_digit();
+        if previous_is_digit.is_some_and(|previous| previous != is_digit) {
+            chunks.push(&name[start..index]);
+            start = index;
+        }
+        previous_is_digit = Some(is_digit);
+    }
+    chunks.push(&name[start..]);
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare_versions, LocalSource};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(
+            compare_versions("v10.graphql", "v9.graphql"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions("2024-01-02.graphql", "2024-01-10.graphql"),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_versions("b.graphql", "a.graphql"),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_versions("v1.graphql", "v1.graphql"),
+            Ordering::Equal
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_the_latest_version() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("supergraph-v9.graphql"), "v9").unwrap();
+        std::fs::write(dir.path().join("supergraph-v10.graphql"), "v10").unwrap();
+        std::fs::write(dir.path().join(".supergraph-v11.graphql.tmp"), "v11").unwrap();
+        std::fs::write(dir.path().join("README.md"), "readme").unwrap();
+
+        let mut source = LocalSource::new(dir.path());
+        let read = source.read().await.unwrap();
+        assert_eq!(read.version, "supergraph-v10.graphql");
+        assert_eq!(read.supergraph.as_deref(), Some("v10"));
+
+        // unchanged
+        assert_eq!(source.read().await.unwrap().supergraph, None);
+
+        std::fs::write(dir.path().join("supergraph-v11.graphql"), "v11").unwrap();
+        let read = source.read().await.unwrap();
+        assert_eq!(read.version, "supergraph-v11.graphql");
+        assert_eq!(read.supergraph.as_deref(), Some("v11"));
+
+        let mut missing = LocalSource::new(dir.path().join("missing.graphql"));
+        assert!(missing.read().await.is_err());
+    }
+}
@@ -1,88 +1,60 @@
 use std::env;
-
-static LOG_LEVEL_NAMES: [&str; 5] = ["ERROR", "WARN", "INFO", "DEBUG", "TRACE"];
-
-#[repr(usize)]
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Debug)]
-pub enum LogLevel {
-    Error,
-    Warn,
-    Info,
-    Debug,
-    Trace,
-}
-
-impl LogLevel {
-    fn from_usize(u: usize) -> Option<LogLevel> {
-        match u {
-            0 => Some(LogLevel::Error),
-            1 => Some(LogLevel::Warn),
-            2 => Some(LogLevel::Info),
-            3 => Some(LogLevel::Debug),
-            4 => Some(LogLevel::Trace),
-            _ => None,
-        }
-    }
-
-    fn from_str(s: &str) -> LogLevel {
-        LOG_LEVEL_NAMES
-            .iter()
-            .position(|&name| name.eq_ignore_ascii_case(s))
-            .map(|p| LogLevel::from_usize(p).expect("Hive failed to read the log level"))
-            .expect("Hive failed to parse the log level filter")
+use tracing::level_filters::LevelFilter;
+use tracing::Dispatch;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+
+static DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// Level of the registry logs set with `HIVE_REGISTRY_LOG`.
+/// Returns an error describing the invalid value, so it can be reported once logging is set up.
+pub fn log_level() -> Result<Option<LevelFilter>, String> {
+    match env::var("HIVE_REGISTRY_LOG") {
+        Ok(value) => value.trim().parse::<LevelFilter>().map(Some).map_err(|_| {
+            format!(
+                "invalid value \"{}\" for HIVE_REGISTRY_LOG, expected one of: off, error, warn, info, debug, trace",
+                value
+            )
+        }),
+        Err(_) => Ok(None),
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Logger {
-    max_level: LogLevel,
+/// Target of every registry log, the `registry*` modules of this crate
+pub fn target() -> &'static str {
+    module_path!().trim_end_matches("_logger")
 }
 
-impl Logger {
-    pub fn new() -> Logger {
-        Self {
-            max_level: LogLevel::from_str(
-                env::var("HIVE_REGISTRY_LOG")
-                    .unwrap_or_else(|_| "info".to_string())
-                    .as_str(),
-            ),
-        }
-    }
+/// The router's log filter (`APOLLO_ROUTER_LOG`) with `HIVE_REGISTRY_LOG` added, so it applies to the registry only.
+/// `None` when `HIVE_REGISTRY_LOG` is not set or invalid, the error is reported by `HiveRegistry::new`.
+pub fn router_log_filter() -> Option<String> {
+    let level = log_level().ok().flatten()?;
+    let directive = format!("{}={}", target(), level);
 
-    fn should_log(&self, level: LogLevel) -> bool {
-        self.max_level >= level
-    }
-
-    #[allow(dead_code)]
-    pub fn trace(&self, message: &str) {
-        if self.should_log(LogLevel::Trace) {
-            println!("TRACE: {}", message);
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn debug(&self, message: &str) {
-        if self.should_log(LogLevel::Debug) {
-            println!("DEBUG: {}", message);
-        }
+    match env::var("APOLLO_ROUTER_LOG") {
+        Ok(filter) if !filter.trim().is_empty() => Some(format!("{},{}", filter.trim(), directive)),
+        _ => Some(format!("{},{}", DEFAULT_LEVEL, directive)),
     }
+}
 
-    pub fn info(&self, message: &str) {
-        if self.should_log(LogLevel::Info) {
-            println!("INFO: {}", message);
-        }
-    }
+/// Writes the registry logs emitted before the router sets up its telemetry, during the initial fetch.
+/// Events of other targets, metrics included, are left out.
+pub fn startup_dispatch(level: Option<LevelFilter>) -> Dispatch {
+    Dispatch::new(
+        tracing_subscriber::fmt()
+            .with_max_level(LevelFilter::TRACE)
+            .finish()
+            .with(Targets::new().with_target(target(), level.unwrap_or(DEFAULT_LEVEL))),
+    )
+}
 
-    #[allow(dead_code)]
-    pub fn warn(&self, message: &str) {
-        if self.should_log(LogLevel::Warn) {
-            println!("WARNING: {}", message);
-        }
-    }
+#[cfg(test)]
+mod tests {
+    use super::target;
 
-    pub fn error(&self, message: &str) {
-        if self.should_log(LogLevel::Error) {
-            println!("ERROR: {}", message);
-        }
+    #[test]
+    fn targets_registry_modules() {
+        assert!(target().ends_with("::registry"));
+        assert!(module_path!().starts_with(target()));
     }
 }
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+static REGISTRY_STATUS: OnceLock<RwLock<RegistryStatus>> = OnceLock::new();
+
+/// State of the registry poller, published after every poll
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct RegistryStatus {
+    /// Unit: seconds since UNIX epoch
+    pub last_successful_fetch: Option<u64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub circuit: String,
+    /// Endpoint that served the latest response
+    pub endpoint: Option<String>,
+    pub etag: Option<String>,
+    /// SHA-256 of the supergraph in use
+    pub supergraph_hash: Option<String>,
+    pub pinned: bool,
+}
+
+impl RegistryStatus {
+    /// Time since the supergraph was last confirmed by the CDN
+    pub fn schema_age(&self) -> Option<Duration> {
+        let last_successful_fetch = UNIX_EPOCH + Duration::from_secs(self.last_successful_fetch?);
+
+        Some(
+            SystemTime::now()
+                .duration_since(last_successful_fetch)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// The router is ready once it has a supergraph that is not older than `max_schema_age`.
+    /// A pinned supergraph never gets old.
+    pub fn ready(&self, max_schema_age: Option<Duration>) -> Result<(), String> {
+        if self.supergraph_hash.is_none() {
+            return Err("no supergraph yet".to_string());
+        }
+
+        let Some(max_schema_age) = max_schema_age else {
+            return Ok(());
+        };
+
+        if self.pinned {
+            return Ok(());
+        }
+
+        match self.schema_age() {
+            Some(age) if age <= max_schema_age => Ok(()),
+            Some(age) => Err(format!(
+                "supergraph was last fetched {}s ago, the maximum age is {}s",
+                age.as_secs(),
+                max_schema_age.as_secs()
+            )),
+            None => Err("supergraph was never fetched from GraphQL Hive CDN".to_string()),
+        }
+    }
+}
+
+pub fn publish(status: RegistryStatus) {
+    match REGISTRY_STATUS
+        .get_or_init(|| RwLock::new(RegistryStatus::default()))
+        .write()
+    {
+        Ok(mut current) => *current = status,
+        Err(e) => tracing::error!(
+            "Unable to acquire lock for RegistryStatus in publish: {}",
+            e
+        ),
+    }
+}
+
+/// The latest status of the registry, `None` until the registry starts.
+pub fn get() -> Option<RegistryStatus> {
+    REGISTRY_STATUS
+        .get()
+        .and_then(|status| status.read().ok())
+        .map(|status| status.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RegistryStatus;
+    use crate::registry_cache::now;
+    use std::time::Duration;
+
+    #[test]
+    fn not_ready_when_the_supergraph_is_too_old() {
+        let mut status = RegistryStatus::default();
+        assert!(status.ready(None).is_err());
+
+        status.supergraph_hash = Some("ABC".to_string());
+        assert!(status.ready(None).is_ok());
+        assert!(status.ready(Some(Duration::from_secs(60))).is_err());
+
+        status.last_successful_fetch = Some(now());
+        assert!(status.ready(Some(Duration::from_secs(60))).is_ok());
+
+        status.last_successful_fetch = Some(now() - 120);
+        assert!(status.ready(Some(Duration::from_secs(60))).is_err());
+
+        status.pinned = true;
+        assert!(status.ready(Some(Duration::from_secs(60))).is_ok());
+    }
+}
@@ -0,0 +1,87 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const OK: &str = "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+/// A local stand-in for the CDN, the usage endpoint or a webhook,
+/// answering each connection with the next canned response.
+/// `None` accepts the connection and never answers, an empty response closes it without answering.
+pub struct StandIn {
+    address: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl StandIn {
+    pub async fn serve(responses: Vec<Option<&'static str>>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+
+        tokio::spawn(async move {
+            let mut hung = Vec::new();
+
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut socket).await;
+                received.lock().unwrap().push(request);
+
+                match response {
+                    Some(response) => {
+                        socket.write_all(response.as_bytes()).await.unwrap();
+                        socket.shutdown().await.unwrap();
+                    }
+                    None => hung.push(socket),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        Self { address, requests }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.address, path)
+    }
+
+    /// The requests received so far, in order
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Reads the head and the body (by its `content-length`) of a request.
+/// The body is counted in bytes, it's compressed in some tests.
+async fn read_request(socket: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buffer = [0; 4096];
+
+    loop {
+        let read = socket.read(&mut buffer).await.unwrap_or(0);
+        request.extend_from_slice(&buffer[..read]);
+
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]);
+            let content_length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().ok())?
+                })
+                .unwrap_or(0);
+
+            if request.len() - (end + 4) >= content_length {
+                return String::from_utf8_lossy(&request).to_string();
+            }
+        }
+
+        if read == 0 {
+            return String::from_utf8_lossy(&request).to_string();
+        }
+    }
+}
@@ -1,4 +1,13 @@
-use crate::agent::{AgentError, ExecutionReport, UsageAgent};
+use crate::agent::{
+    AgentError, BufferLimits, ExecutionReport, OverflowPolicy, RetryPolicy, UsageAgent,
+    UsageRecorder,
+};
+use crate::backoff::Backoff;
+use crate::http_client::HttpClientConfig;
+use crate::metrics::{self, DropReason};
+use crate::usage_compression::{BodyCompression, Compression};
+use crate::usage_spill::SpillQueue;
+use crate::usage_window;
 use apollo_router::layers::ServiceBuilderExt;
 use apollo_router::plugin::Plugin;
 use apollo_router::plugin::PluginInit;
@@ -14,15 +23,37 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::env;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, OnceLock};
 use std::time::Instant;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
 use tower::BoxError;
 use tower::ServiceBuilder;
 use tower::ServiceExt;
 
 pub(crate) static OPERATION_CONTEXT: &str = "hive::operation_context";
 
+static LIFECYCLE: OnceLock<Mutex<Lifecycle>> = OnceLock::new();
+
+/// Keeps buffered reports across plugin reloads and router shutdown.
+/// It assumes a single `hive.usage` plugin per process: an instance created while another one is
+/// still in use is taken for its reload, and receives its buffered reports once the other is dropped.
+#[derive(Default)]
+struct Lifecycle {
+    /// The agent of the latest plugin instance and its shutdown timeout.
+    /// A reloaded plugin hands its buffered reports over to it.
+    current: Option<(UsageAgent, Duration)>,
+    /// The final report of a dropped plugin without a successor
+    final_report: Option<JoinHandle<()>>,
+}
+
+fn lifecycle() -> MutexGuard<'static, Lifecycle> {
+    LIFECYCLE
+        .get_or_init(|| Mutex::new(Lifecycle::default()))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
 #[derive(Serialize, Deserialize)]
 struct OperationContext {
     pub(crate) client_name: Option<String>,
@@ -44,6 +75,7 @@ struct OperationConfig {
 struct UsagePlugin {
     config: OperationConfig,
     agent: Option<Arc<Mutex<UsageAgent>>>,
+    shutdown_timeout: Duration,
 }
 
 #[derive(Clone, Debug, Deserialize, JsonSchema)]
@@ -62,6 +94,16 @@ struct Config {
     /// A maximum number of operations to hold in a buffer before sending to GraphQL Hive
     /// Default: 1000
     buffer_size: Option<usize>,
+    /// A maximum number of operations the buffer holds, when reports can't be sent fast enough
+    /// Default: 10000
+    buffer_max_operations: Option<usize>,
+    /// A maximum (estimated) size of the buffered operations
+    /// Unit: bytes
+    /// Default: 52428800 (50 MiB)
+    buffer_max_bytes: Option<usize>,
+    /// What to drop when the buffer is full: drop_oldest, drop_newest or sample
+    /// Default: drop_oldest
+    buffer_overflow: Option<OverflowPolicy>,
     /// A timeout for only the connect phase of a request to GraphQL Hive
     /// Unit: seconds
     /// Default: 5 (s)
@@ -73,6 +115,42 @@ struct Config {
     /// Accept invalid SSL certificates
     /// Default: false
     accept_invalid_certs: Option<bool>,
+    /// How many times a report is retried when GraphQL Hive can't be reached or responds with an error
+    /// Default: 3
+    max_retries: Option<u32>,
+    /// A delay before the first retry, doubled (with jitter) on every retry
+    /// Unit: milliseconds
+    /// Default: 500 (ms)
+    retry_delay: Option<u64>,
+    /// A maximum delay between retries. A longer `Retry-After` pauses reporting instead.
+    /// Unit: milliseconds
+    /// Default: 30000 (30s)
+    retry_max_delay: Option<u64>,
+    /// Compression of the report bodies: none, gzip or zstd.
+    /// Only for endpoints that decompress request bodies, GraphQL Hive's usage service doesn't.
+    /// Default: none
+    compression: Option<Compression>,
+    /// Smaller reports are sent uncompressed
+    /// Unit: bytes
+    /// Default: 1024
+    compression_threshold: Option<usize>,
+    /// Directory where reports that failed to be sent are kept, and replayed from once GraphQL Hive is reachable again.
+    /// Disabled when not set.
+    spill_dir: Option<String>,
+    /// A maximum size of the spilled reports, the oldest ones are dropped first
+    /// Unit: bytes
+    /// Default: 104857600 (100 MiB)
+    spill_max_bytes: Option<u64>,
+    /// Spilled reports older than this are dropped
+    /// Unit: seconds
+    /// Default: 86400 (24h)
+    spill_max_age: Option<u64>,
+    /// How long the final report may take when the router shuts down
+    /// Unit: seconds
+    /// Default: 5 (s)
+    shutdown_timeout: Option<u64>,
+    /// Proxy, extra root certificates and client certificate of the usage reporting client
+    http: Option<HttpClientConfig>,
 }
 
 impl Default for Config {
@@ -85,8 +163,21 @@ impl Default for Config {
             client_version_header: Some(String::from("graphql-client-version")),
             accept_invalid_certs: Some(false),
             buffer_size: Some(1000),
+            buffer_max_operations: Some(10000),
+            buffer_max_bytes: Some(50 * 1024 * 1024),
+            buffer_overflow: Some(OverflowPolicy::DropOldest),
             connect_timeout: Some(5),
             request_timeout: Some(15),
+            max_retries: Some(3),
+            retry_delay: Some(500),
+            retry_max_delay: Some(30000),
+            compression: Some(Compression::None),
+            compression_threshold: Some(1024),
+            spill_dir: None,
+            spill_max_bytes: Some(100 * 1024 * 1024),
+            spill_max_age: Some(86400),
+            shutdown_timeout: Some(5),
+            http: None,
         }
     }
 }
@@ -134,10 +225,13 @@ impl UsagePlugin {
                 Some(name) => {
                     if excluded_operation_names.contains(name) {
                         dropped = true;
+                        metrics::usage_operation_dropped(DropReason::Excluded);
                     }
                 }
                 None => {}
             }
+        } else {
+            metrics::usage_operation_dropped(DropReason::Sampling);
         }
 
         let _ = context.insert(
@@ -181,6 +275,20 @@ impl Plugin for UsagePlugin {
             .buffer_size
             .or(default_config.buffer_size)
             .expect("buffer_size has no default value");
+        let buffer_limits = BufferLimits {
+            max_operations: user_config
+                .buffer_max_operations
+                .or(default_config.buffer_max_operations)
+                .expect("buffer_max_operations has no default value"),
+            max_bytes: user_config
+                .buffer_max_bytes
+                .or(default_config.buffer_max_bytes)
+                .expect("buffer_max_bytes has no default value"),
+            overflow: user_config
+                .buffer_overflow
+                .or(default_config.buffer_overflow)
+                .expect("buffer_overflow has no default value"),
+        };
         let accept_invalid_certs = user_config
             .accept_invalid_certs
             .or(default_config.accept_invalid_certs)
@@ -193,11 +301,87 @@ impl Plugin for UsagePlugin {
             .request_timeout
             .or(default_config.request_timeout)
             .expect("request_timeout has no default value");
+        let retry = RetryPolicy {
+            max_retries: user_config
+                .max_retries
+                .or(default_config.max_retries)
+                .expect("max_retries has no default value"),
+            backoff: Backoff::new(
+                Duration::from_millis(
+                    user_config
+                        .retry_delay
+                        .or(default_config.retry_delay)
+                        .expect("retry_delay has no default value"),
+                ),
+                Duration::from_millis(
+                    user_config
+                        .retry_max_delay
+                        .or(default_config.retry_max_delay)
+                        .expect("retry_max_delay has no default value"),
+                ),
+            ),
+        };
+        let compression = BodyCompression {
+            algorithm: user_config
+                .compression
+                .or(default_config.compression)
+                .expect("compression has no default value"),
+            threshold: user_config
+                .compression_threshold
+                .or(default_config.compression_threshold)
+                .expect("compression_threshold has no default value"),
+        };
+        if compression.algorithm != Compression::None && env::var("HIVE_ENDPOINT").is_err() {
+            tracing::warn!(
+                "GraphQL Hive's usage service doesn't accept compressed reports, use compression with a HIVE_ENDPOINT that decompresses them"
+            );
+        }
+        let spill = user_config.spill_dir.clone().map(|spill_dir| {
+            SpillQueue::new(
+                spill_dir,
+                user_config
+                    .spill_max_bytes
+                    .or(default_config.spill_max_bytes)
+                    .expect("spill_max_bytes has no default value"),
+                Duration::from_secs(
+                    user_config
+                        .spill_max_age
+                        .or(default_config.spill_max_age)
+                        .expect("spill_max_age has no default value"),
+                ),
+            )
+        });
+        let shutdown_timeout = Duration::from_secs(
+            user_config
+                .shutdown_timeout
+                .or(default_config.shutdown_timeout)
+                .expect("shutdown_timeout has no default value"),
+        );
 
         if enabled {
             tracing::info!("Starting GraphQL Hive Usage plugin");
         }
 
+        let agent = match enabled {
+            true => Some(UsageAgent::new(
+                init.supergraph_sdl.to_string(),
+                token,
+                endpoint,
+                buffer_size,
+                connect_timeout,
+                request_timeout,
+                accept_invalid_certs,
+                &user_config.http.clone().unwrap_or_default().with_env(),
+                buffer_limits,
+                spill,
+                retry,
+                compression,
+            )?),
+            false => None,
+        };
+        // The previous instance, if any, hands its buffered reports over to this one once it's dropped
+        lifecycle().current = agent.clone().map(|agent| (agent, shutdown_timeout));
+
         Ok(UsagePlugin {
             config: OperationConfig {
                 sample_rate: user_config
@@ -214,18 +398,8 @@ impl Plugin for UsagePlugin {
                     .or(default_config.client_version_header)
                     .expect("client_version_header has no default value"),
             },
-            agent: match enabled {
-                true => Some(Arc::new(Mutex::new(UsageAgent::new(
-                    init.supergraph_sdl.to_string(),
-                    token,
-                    endpoint,
-                    buffer_size,
-                    connect_timeout,
-                    request_timeout,
-                    accept_invalid_certs,
-                )))),
-                false => None,
-            },
+            agent: agent.map(|agent| Arc::new(Mutex::new(agent))),
+            shutdown_timeout,
         })
     }
 
@@ -234,10 +408,16 @@ impl Plugin for UsagePlugin {
         match self.agent.clone() {
             None => ServiceBuilder::new().service(service).boxed(),
             Some(agent) => {
+                let recorder = agent
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .usage_recorder();
+
                 ServiceBuilder::new()
                     .map_future_with_request_data(
                         move |req: &supergraph::Request| {
                             Self::populate_context(config.clone(), req);
+                            record_usage(&recorder, &req.context);
                             req.context.clone()
                         },
                         move |ctx: Context, fut| {
@@ -339,6 +519,24 @@ impl Plugin for UsagePlugin {
     }
 }
 
+/// Feeds the usage window of the registry with every operation, sampled and excluded ones included
+fn record_usage(recorder: &UsageRecorder, context: &Context) {
+    if !usage_window::is_enabled() {
+        return;
+    }
+
+    let Ok(Some(operation)) = context.get::<_, OperationContext>(OPERATION_CONTEXT) else {
+        return;
+    };
+
+    recorder.record(
+        operation.operation_body,
+        operation.operation_name,
+        operation.client_name,
+        operation.client_version,
+    );
+}
+
 fn try_add_report(agent: Arc<Mutex<UsageAgent>>, execution_report: ExecutionReport) {
     agent
         .lock()
@@ -354,7 +552,66 @@ AgentError::Lock(e.to_string())
 impl Drop for UsagePlugin {
     fn drop(&mut self) {
         tracing::debug!("UsagePlugin has been dropped!");
-        // TODO: flush the buffer
+
+        // A poisoned lock still holds the agent, its reports are not lost
+        let Some(agent) = self
+            .agent
+            .take()
+            .map(|agent| agent.lock().unwrap_or_else(|e| e.into_inner()).clone())
+        else {
+            return;
+        };
+        agent.stop();
+
+        let mut lifecycle = lifecycle();
+        let reloaded = lifecycle
+            .current
+            .as_ref()
+            .map(|(current, _)| current.clone())
+            .filter(|current| !current.is_same(&agent));
+
+        match reloaded {
+            // The plugin was reloaded, the new instance sends the reports
+            Some(current) => match agent.hand_over(&current) {
+                Ok(count) => tracing::debug!(
+                    "Handed {} buffered operations over to the reloaded usage plugin",
+                    count
+                ),
+                Err(e) => tracing::error!("Error handing reports over: {}", e),
+            },
+            None => {
+                lifecycle.current = None;
+
+                match tokio::runtime::Handle::try_current() {
+                    Ok(runtime) => {
+                        let timeout = self.shutdown_timeout;
+                        lifecycle.final_report = Some(runtime.spawn(async move {
+                            agent.shutdown(timeout).await;
+                        }));
+                    }
+                    Err(_) => {
+                        tracing::warn!("Unable to send the final usage report: no tokio runtime")
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Sends the reports buffered by the usage plugin and stops it.
+/// Call it once the router has stopped, before the tokio runtime shuts down.
+pub async fn shutdown() {
+    let (current, final_report) = {
+        let mut lifecycle = lifecycle();
+        (lifecycle.current.take(), lifecycle.final_report.take())
+    };
+
+    if let Some((agent, timeout)) = current {
+        agent.shutdown(timeout).await;
+    }
+
+    if let Some(final_report) = final_report {
+        let _ = final_report.await;
     }
 }
 
@@ -0,0 +1,138 @@
+use flate2::write::GzEncoder;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::io::Write;
+
+/// Compression of the usage report bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Value of the `Content-Encoding` header
+    fn encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BodyCompression {
+    pub algorithm: Compression,
+    /// Smaller bodies are sent as they are, compressing them isn't worth it
+    /// Unit: bytes
+    pub threshold: usize,
+}
+
+impl Default for BodyCompression {
+    fn default() -> Self {
+        Self {
+            algorithm: Compression::None,
+            threshold: 1024,
+        }
+    }
+}
+
+/// A request body, and its `Content-Encoding` when compressed
+#[derive(Debug, Clone)]
+pub struct EncodedBody {
+    pub body: Vec<u8>,
+    pub encoding: Option<&'static str>,
+}
+
+impl BodyCompression {
+    /// Compresses the body, or returns it as it is when it's below the threshold.
+    /// Falls back to the uncompressed body when the compression fails.
+    pub fn encode(&self, body: Vec<u8>) -> EncodedBody {
+        let encoding = match self.algorithm.encoding() {
+            Some(encoding) if body.len() >= self.threshold => encoding,
+            _ => {
+                return EncodedBody {
+                    body,
+                    encoding: None,
+                }
+            }
+        };
+
+        match compress(self.algorithm, &body) {
+            Ok(compressed) => EncodedBody {
+                body: compressed,
+                encoding: Some(encoding),
+            },
+            Err(e) => {
+                tracing::warn!(
+                    "Unable to compress the usage report ({}), sending it uncompressed: {}",
+                    encoding,
+                    e
+                );
+                EncodedBody {
+                    body,
+                    encoding: None,
+                }
+            }
+        }
+    }
+}
+
+fn compress(algorithm: Compression, body: &[u8]) -> std::io::Result<Vec<u8>> {
+    match algorithm {
+        Compression::None => Ok(body.to_vec()),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()
+        }
+        Compression::Zstd => zstd::encode_all(body, zstd::DEFAULT_COMPRESSION_LEVEL),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BodyCompression, Compression};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compresses_above_the_threshold() {
+        let body = r#"{"operation":"query { products { id name } }"}"#
+            .repeat(100)
+            .into_bytes();
+
+        let gzip = BodyCompression {
+            algorithm: Compression::Gzip,
+            threshold: 1024,
+        };
+        let encoded = gzip.encode(body.clone());
+        assert_eq!(encoded.encoding, Some("gzip"));
+        assert!(encoded.body.len() < body.len());
+        let mut decoded = Vec::new();
+        GzDecoder::new(encoded.body.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        let zstd = BodyCompression {
+            algorithm: Compression::Zstd,
+            threshold: 1024,
+        };
+        let encoded = zstd.encode(body.clone());
+        assert_eq!(encoded.encoding, Some("zstd"));
+        assert_eq!(zstd::decode_all(encoded.body.as_slice()).unwrap(), body);
+
+        // below the threshold
+        let encoded = gzip.encode(b"{}".to_vec());
+        assert_eq!(encoded.encoding, None);
+        assert_eq!(encoded.body, b"{}");
+
+        let encoded = BodyCompression::default().encode(body.clone());
+        assert_eq!(encoded.encoding, None);
+        assert_eq!(encoded.body, body);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::fs_util::write_atomically;
+use crate::metrics::{self, DropReason};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a report stays claimed before it's back in the queue, the agent that claimed it is gone by then
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Usage reports that failed to be sent, kept on disk until the endpoint recovers.
+/// Files are named `<saved at (ms)>-<operations>-<random>.json`, so they sort oldest first
+/// and survive a restart without an index.
+/// A report being sent is renamed to `<name>.json.<claimed at (ms)>.inflight`.
+#[derive(Debug, Clone)]
+pub struct SpillQueue {
+    dir: PathBuf,
+    /// Unit: bytes
+    max_bytes: u64,
+    max_age: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpilledReport {
+    path: PathBuf,
+    /// Unit: milliseconds since UNIX epoch
+    saved_at: u64,
+    pub operations: usize,
+    size: u64,
+}
+
+impl SpilledReport {
+    fn parse(path: PathBuf, size: u64) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".json")?;
+        let mut parts = name.split('-');
+        let saved_at = parts.next()?.parse().ok()?;
+        let operations = parts.next()?.parse().ok()?;
+
+        Some(Self {
+            path,
+            saved_at,
+            operations,
+            size,
+        })
+    }
+}
+
+/// The path of a claimed report once released, and when it was claimed
+fn parse_claim(path: &Path) -> Option<(PathBuf, u64)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".inflight")?;
+    let (name, claimed_at) = name.rsplit_once('.')?;
+
+    Some((path.with_file_name(name), claimed_at.parse().ok()?))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl SpillQueue {
+    pub fn new(dir: impl Into<PathBuf>, max_bytes: u64, max_age: Duration) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes,
+            max_age,
+        }
+    }
+
+    /// Saves the JSON body of a report, then forgets the oldest reports beyond `max_bytes`.
+    pub async fn save(&self, body: &[u8], operations: usize) -> Result<(), String> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .map_err(|e| format!("failed to create {}: {}", self.dir.display(), e))?;
+
+        let body = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+        let path = self.dir.join(format!(
+            "{:016}-{}-{:016x}.json",
+            now_millis(),
+            operations,
+            rand::random::<u64>()
+        ));
+        write_atomically(&path.to_string_lossy(), body)
+            .await
+            .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+
+        let entries = self.entries().await?;
+        let mut total: u64 = entries.iter().map(|entry| entry.size).sum();
+
+        for entry in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+
+            total -= entry.size;
+            self.evict(&entry).await;
+        }
+
+        Ok(())
+    }
+
+    /// Spilled reports, oldest first. The ones older than `max_age` are removed.
+    pub async fn entries(&self) -> Result<Vec<SpilledReport>, String> {
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(format!("failed to read {}: {}", self.dir.display(), e)),
+        };
+        let mut entries = Vec::new();
+
+        while let Some(entry) = dir.next_entry().await.map_err(|e| e.to_string())? {
+            let size = match entry.metadata().await {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                _ => continue,
+            };
+
+            let mut path = entry.path();
+            if let Some((released, claimed_at)) = parse_claim(&path) {
+                let claimed_for = now_millis().saturating_sub(claimed_at);
+                if claimed_for < CLAIM_TIMEOUT.as_millis() as u64
+                    || tokio::fs::rename(&path, &released).await.is_err()
+                {
+                    continue;
+                }
+                path = released;
+            }
+
+            // Temporary files of `write_atomically` start with a dot and don't parse
+            if let Some(report) = SpilledReport::parse(path, size) {
+                entries.push(report);
+            }
+        }
+
+        entries.sort_by_key(|entry| (entry.saved_at, entry.path.clone()));
+
+        let expired_before = now_millis().saturating_sub(self.max_age.as_millis() as u64);
+        let (expired, entries): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|entry| entry.saved_at < expired_before);
+
+        for entry in expired {
+            self.evict(&entry).await;
+        }
+
+        Ok(entries)
+    }
+
+    pub async fn read(&self, entry: &SpilledReport) -> Result<Vec<u8>, String> {
+        tokio::fs::read(&entry.path)
+            .await
+            .map_err(|e| format!("failed to read {}: {}", entry.path.display(), e))
+    }
+
+    /// Takes a report out of the queue before sending it, so agents sharing the directory don't send it twice.
+    /// Returns `None` when another agent claimed it first.
+    pub async fn claim(&self, entry: &SpilledReport) -> Option<SpilledReport> {
+        let mut claimed = entry.path.clone().into_os_string();
+        claimed.push(format!(".{}.inflight", now_millis()));
+        let claimed = PathBuf::from(claimed);
+
+        tokio::fs::rename(&entry.path, &claimed).await.ok()?;
+
+        Some(SpilledReport {
+            path: claimed,
+            ..entry.clone()
+        })
+    }
+
+    /// Puts a claimed report back in the queue, for the next attempt
+    pub async fn release(&self, entry: &SpilledReport) {
+        let Some((released, _)) = parse_claim(&entry.path) else {
+            return;
+        };
+
+        if let Err(e) = tokio::fs::rename(&entry.path, &released).await {
+            tracing::warn!(
+                "Failed to release spilled usage report {}: {}",
+                entry.path.display(),
+                e
+            );
+        }
+    }
+
+    pub async fn remove(&self, entry: &SpilledReport) {
+        if let Err(e) = tokio::fs::remove_file(&entry.path).await {
+            tracing::warn!(
+                "Failed to remove spilled usage report {}: {}",
+                entry.path.display(),
+                e
+            );
+        }
+    }
+
+    /// Removes a report that is never going to be sent
+    async fn evict(&self, entry: &SpilledReport) {
+        self.remove(entry).await;
+        metrics::usage_operations_dropped(DropReason::SpillEvicted, entry.operations as u64);
+        tracing::warn!(
+            "Dropped a spilled usage report of {} operations (size and age limits)",
+            entry.operations
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpillQueue;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn keeps_reports_oldest_first_within_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let body = br#"{"size":1,"map":{},"operations":[]}"#;
+        let spill = SpillQueue::new(dir.path(), body.len() as u64 * 2, Duration::from_secs(60));
+
+        for operations in 1..=3 {
+            spill.save(body, operations).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+
+        // the oldest one is over the size limit
+        let entries = spill.entries().await.unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.operations)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(spill.read(&entries[0]).await.unwrap(), body.to_vec());
+
+        // a restart reads the same directory
+        let restarted = SpillQueue::new(dir.path(), u64::MAX, Duration::from_secs(60));
+        assert_eq!(restarted.entries().await.unwrap(), entries);
+
+        spill.remove(&entries[0]).await;
+        assert_eq!(spill.entries().await.unwrap().len(), 1);
+
+        // claimed by one agent at a time
+        let entry = spill.entries().await.unwrap().remove(0);
+        let claimed = spill.claim(&entry).await.unwrap();
+        assert!(spill.claim(&entry).await.is_none());
+        assert!(spill.entries().await.unwrap().is_empty());
+        assert_eq!(spill.read(&claimed).await.unwrap(), body.to_vec());
+        spill.release(&claimed).await;
+        assert_eq!(spill.entries().await.unwrap(), vec![entry]);
+
+        let expired = SpillQueue::new(dir.path(), u64::MAX, Duration::ZERO);
+        tokio::time::sleep(Duration::from_millis(2)).await;
+        assert!(expired.entries().await.unwrap().is_empty());
+        assert!(spill.entries().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+static USAGE_WINDOW: RwLock<Option<Arc<UsageWindow>>> = RwLock::new(None);
+/// Whether `USAGE_WINDOW` is set, checked on the request path without locking
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Operations listed per coordinate, the least recently seen one makes room for a new one.
+/// A coordinate stays used either way, only the operations named in the logs are capped.
+const MAX_OPERATIONS_PER_COORDINATE: usize = 100;
+/// How often `record` drops what fell out of the window, at most
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts collecting schema coordinates used by live traffic, or changes the duration of the window (a reload).
+/// The usage plugin records coordinates only once the window is enabled.
+pub fn enable(window: Duration) -> Arc<UsageWindow> {
+    let window = replace(&USAGE_WINDOW, Some(window)).expect("the usage window was just enabled");
+    ENABLED.store(true, Ordering::Relaxed);
+    window
+}
+
+/// Stops collecting schema coordinates, a reload turned the usage guard off
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+    replace(&USAGE_WINDOW, None);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn get() -> Option<Arc<UsageWindow>> {
+    USAGE_WINDOW
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Sets the window of `slot`. What was recorded so far is kept when only the duration changes.
+fn replace(
+    slot: &RwLock<Option<Arc<UsageWindow>>>,
+    window: Option<Duration>,
+) -> Option<Arc<UsageWindow>> {
+    let mut state = slot.write().unwrap_or_else(|e| e.into_inner());
+
+    *state = match (state.take(), window) {
+        (_, None) => None,
+        (Some(current), Some(window)) if current.window == window => Some(current),
+        (Some(current), Some(window)) => Some(Arc::new(current.resized(window))),
+        (None, Some(window)) => Some(Arc::new(UsageWindow::new(window))),
+    };
+
+    state.clone()
+}
+
+/// An operation that used a schema coordinate
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct OperationRef {
+    /// Name of the operation, or its hash when it's anonymous
+    pub operation: String,
+    pub client: Option<String>,
+}
+
+impl fmt::Display for OperationRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.client {
+            Some(client) => write!(f, "{} (client: {})", self.operation, client),
+            None => write!(f, "{}", self.operation),
+        }
+    }
+}
+
+/// Schema coordinates used within a rolling time window, with the operations that used them
+#[derive(Debug)]
+pub struct UsageWindow {
+    window: Duration,
+    coordinates: Mutex<Coordinates>,
+}
+
+#[derive(Debug, Default)]
+struct Coordinates {
+    used: HashMap<String, HashMap<OperationRef, Instant>>,
+    pruned_at: Option<Instant>,
+}
+
+impl Coordinates {
+    /// Forgets the operations (and coordinates) last seen before `oldest`
+    fn prune(&mut self, now: Instant, oldest: Option<Instant>) {
+        if let Some(oldest) = oldest {
+            self.used.retain(|_, operations| {
+                operations.retain(|_, last_seen| *last_seen >= oldest);
+                !operations.is_empty()
+            });
+        }
+
+        self.pruned_at = Some(now);
+    }
+}
+
+impl UsageWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            coordinates: Mutex::new(Coordinates::default()),
+        }
+    }
+
+    /// A copy with another duration, coordinates that fall out of it are forgotten on the next prune
+    fn resized(&self, window: Duration) -> Self {
+        let used = match self.coordinates.lock() {
+            Ok(state) => state.used.clone(),
+            Err(e) => e.into_inner().used.clone(),
+        };
+
+        Self {
+            window,
+            coordinates: Mutex::new(Coordinates {
+                used,
+                pruned_at: None,
+            }),
+        }
+    }
+
+    pub fn record(&self, coordinates: &[String], operation: OperationRef) {
+        let now = Instant::now();
+
+        let Ok(mut state) = self.coordinates.lock() else {
+            tracing::error!("Unable to acquire lock for UsageWindow in record");
+            return;
+        };
+
+        for coordinate in coordinates {
+            let operations = state.used.entry(coordinate.clone()).or_default();
+            operations.insert(operation.clone(), now);
+
+            if operations.len() > MAX_OPERATIONS_PER_COORDINATE {
+                let least_recent = operations
+                    .iter()
+                    .filter(|(other, _)| **other != operation)
+                    .min_by_key(|(_, last_seen)| **last_seen)
+                    .map(|(other, _)| other.clone());
+
+                if let Some(least_recent) = least_recent {
+                    operations.remove(&least_recent);
+                }
+            }
+        }
+
+        // Client names and versions come from request headers, what fell out of the window doesn't pile up
+        let pruned_recently = state.pruned_at.is_some_and(|pruned_at| {
+            now.duration_since(pruned_at) < self.window.min(PRUNE_INTERVAL)
+        });
+        if !pruned_recently {
+            state.prune(now, now.checked_sub(self.window));
+        }
+    }
+
+    /// Coordinates matching `predicate` that were used within the window, with the operations using them.
+    /// Coordinates that fell out of the window are forgotten.
+    pub fn used(
+        &self,
+        predicate: impl Fn(&str) -> bool,
+    ) -> BTreeMap<String, BTreeSet<OperationRef>> {
+        let mut result = BTreeMap::new();
+
+        let Ok(mut state) = self.coordinates.lock() else {
+            tracing::error!("Unable to acquire lock for UsageWindow in used");
+            return result;
+        };
+
+        let now = Instant::now();
+        state.prune(now, now.checked_sub(self.window));
+
+        for (coordinate, operations) in state.used.iter() {
+            if predicate(coordinate) {
+                result.insert(
+                    coordinate.clone(),
+                    operations.keys().cloned().collect::<BTreeSet<_>>(),
+                );
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replace, OperationRef, UsageWindow, MAX_OPERATIONS_PER_COORDINATE};
+    use std::sync::RwLock;
+    use std::time::Duration;
+
+    fn operation(name: &str, client: Option<&str>) -> OperationRef {
+        OperationRef {
+            operation: name.to_string(),
+            client: client.map(|c| c.to_string()),
+        }
+    }
+
+    #[test]
+    fn collects_operations_per_coordinate() {
+        let window = UsageWindow::new(Duration::from_secs(60));
+
+        window.record(
+            &["Query.products".to_string(), "Product.price".to_string()],
+            operation("Products", Some("web")),
+        );
+        window.record(&["Product.price".to_string()], operation("Price", None));
+
+        let used = window.used(|coordinate| coordinate.starts_with("Product."));
+
+        assert_eq!(used.len(), 1);
+        assert_eq!(
+            used["Product.price"].iter().cloned().collect::<Vec<_>>(),
+            vec![operation("Price", None), operation("Products", Some("web"))]
+        );
+    }
+
+    #[test]
+    fn forgets_coordinates_outside_of_the_window() {
+        let window = UsageWindow::new(Duration::ZERO);
+
+        window.record(&["Query.products".to_string()], operation("Products", None));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert!(window.used(|_| true).is_empty());
+
+        // without a supergraph change, recording drops them too
+        window.record(&["Query.products".to_string()], operation("Products", None));
+        std::thread::sleep(Duration::from_millis(5));
+        window.record(&["Query.users".to_string()], operation("Users", None));
+        assert!(!window
+            .coordinates
+            .lock()
+            .unwrap()
+            .used
+            .contains_key("Query.products"));
+    }
+
+    #[test]
+    fn caps_the_operations_per_coordinate() {
+        let window = UsageWindow::new(Duration::from_secs(60));
+
+        for version in 0..=MAX_OPERATIONS_PER_COORDINATE {
+            window.record(
+                &["Query.products".to_string()],
+                operation("Products", Some(&format!("web@{}", version))),
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let used = window.used(|_| true);
+        assert_eq!(used["Query.products"].len(), MAX_OPERATIONS_PER_COORDINATE);
+        // the least recently seen one made room
+        assert!(!used["Query.products"].contains(&operation("Products", Some("web@0"))));
+    }
+
+    #[test]
+    fn replaces_the_window_on_reload() {
+        let slot = RwLock::new(None);
+
+        let window = replace(&slot, Some(Duration::from_secs(60))).unwrap();
+        window.record(&["Query.products".to_string()], operation("Products", None));
+        assert!(std::sync::Arc::ptr_eq(
+            &window,
+            &replace(&slot, Some(Duration::from_secs(60))).unwrap()
+        ));
+
+        // a changed window applies, and keeps what was recorded
+        let resized = replace(&slot, Some(Duration::from_secs(120))).unwrap();
+        assert_eq!(resized.window, Duration::from_secs(120));
+        assert!(resized.used(|_| true).contains_key("Query.products"));
+
+        // the usage guard was turned off
+        assert!(replace(&slot, None).is_none());
+        assert!(slot.read().unwrap().is_none());
+    }
+}